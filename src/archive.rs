@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::Episode;
+
+/// Record of completed downloads, consulted to avoid downloading episodes again.
+///
+/// The archive is a text file with one line per downloaded episode, containing the
+/// tab-separated feed URL, item GUID, enclosure URL and SHA-256 hash of the file.
+/// Episodes are identified by the feed URL and GUID, so renaming or moving the
+/// downloaded files does not cause them to be downloaded again.
+pub(crate) struct DownloadArchive {
+    inner: Mutex<ArchiveInner>,
+}
+
+struct ArchiveInner {
+    /// Feed URL and GUID of each recorded episode.
+    entries: HashSet<(String, String)>,
+    /// Archive file opened for appending new records.
+    file: File,
+}
+
+impl DownloadArchive {
+    /// Open the archive at `path`, creating it and its directory if they do not exist.
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent).with_context(|| {
+                format!(
+                    "Failed to create download archive directory {:?}",
+                    parent.to_string_lossy()
+                )
+            })?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .with_context(|| {
+                format!(
+                    "Failed to open download archive {:?}",
+                    path.to_string_lossy()
+                )
+            })?;
+        let mut entries = HashSet::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            let mut fields = line.split('\t');
+            if let (Some(feed), Some(guid)) = (fields.next(), fields.next()) {
+                entries.insert((feed.to_owned(), guid.to_owned()));
+            }
+        }
+        log::debug!("{} episodes in download archive", entries.len());
+        Ok(Self {
+            inner: Mutex::new(ArchiveInner { entries, file }),
+        })
+    }
+
+    /// Whether the episode from the given feed has already been downloaded.
+    pub(crate) fn contains(&self, feed: &str, episode: &Episode) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .contains(&archive_key(feed, episode))
+    }
+
+    /// Record a completed download of the episode to `output_file`.
+    pub(crate) fn record(
+        &self,
+        feed: &str,
+        episode: &Episode,
+        output_file: &Path,
+    ) -> anyhow::Result<()> {
+        let hash = sha256_hex(output_file)?;
+        let (feed, id) = archive_key(feed, episode);
+        let line = format!(
+            "{feed}\t{id}\t{}\t{hash}",
+            clean_field(episode.audio_url.as_str())
+        );
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.insert((feed, id)) {
+            writeln!(inner.file, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Feed URL and episode identifier, as stored in the archive.
+fn archive_key(feed: &str, episode: &Episode) -> (String, String) {
+    (clean_field(feed), clean_field(episode.archive_id()))
+}
+
+/// Remove tabs and newlines, which would corrupt the archive's line format.
+fn clean_field(field: &str) -> String {
+    field.replace(['\t', '\r', '\n'], " ")
+}
+
+/// Hex-encoded SHA-256 hash of the file's contents.
+fn sha256_hex(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
@@ -0,0 +1,165 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::bail;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// Largest image that will be downloaded, well above the 3000x3000 pixel artwork
+/// Apple asks podcasts for.
+const MAX_ARTWORK_SIZE: u64 = 20 * 1024 * 1024;
+
+/// Name of the podcast artwork file saved with --cover, without its extension.
+const COVER_NAME: &str = "cover";
+
+/// Downloaded podcast or episode artwork.
+#[derive(Debug)]
+pub(crate) struct Artwork {
+    /// The image file's contents.
+    pub data: Vec<u8>,
+    /// Image mime type, either JPEG or PNG.
+    pub mime_type: &'static str,
+}
+
+impl Artwork {
+    /// Check the downloaded image is a JPEG or PNG, which are the formats that
+    /// podcast apps accept and that can be embedded in both ID3 tags and MP4 files.
+    fn new(data: Vec<u8>) -> anyhow::Result<Self> {
+        let mime_type = match data.as_slice() {
+            [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+            [0x89, b'P', b'N', b'G', ..] => "image/png",
+            _ => bail!("Artwork is not a JPEG or PNG image"),
+        };
+        Ok(Self { data, mime_type })
+    }
+
+    /// Extension for the image's file type.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self.mime_type {
+            "image/png" => "png",
+            _ => "jpg",
+        }
+    }
+}
+
+/// What is known about the image at a URL.
+#[derive(Debug, Clone, Copy)]
+enum Fetched {
+    /// The image was downloaded, and has this hash of its contents.
+    Image([u8; 32]),
+    /// The image couldn't be downloaded, or isn't a JPEG or PNG.
+    Failed,
+}
+
+/// The result of downloading an image, shared by everything that asks for it.
+type Entry = Arc<Mutex<Option<Fetched>>>;
+
+/// Number of the most recently used images kept in memory when nothing is using
+/// them.
+const RECENT_ARTWORK: usize = 8;
+
+/// Artwork downloaded during a run, shared by every feed and download thread.
+///
+/// Each image URL is downloaded once, however many episodes use it, and images with
+/// identical contents share one copy in memory, as feeds often give every episode
+/// the podcast's artwork at URLs that differ only in their query. Only images in
+/// use and the few most recently used are kept in memory, so an image needed again
+/// after being dropped is downloaded again.
+#[derive(Debug, Default)]
+pub(crate) struct ArtworkCache {
+    by_url: Mutex<HashMap<Url, Entry>>,
+    by_hash: Mutex<HashMap<[u8; 32], Weak<Artwork>>>,
+    /// The most recently used images, most recent first.
+    recent: Mutex<VecDeque<Arc<Artwork>>>,
+}
+
+impl ArtworkCache {
+    /// Get the image at the URL, downloading it if it isn't in memory.
+    ///
+    /// Returns None if the image can't be downloaded or isn't a JPEG or PNG, which
+    /// is logged the first time only.
+    pub(crate) fn get(&self, url: &Url) -> Option<Arc<Artwork>> {
+        let entry = Arc::clone(self.by_url.lock().unwrap().entry(url.clone()).or_default());
+        // Threads wanting the same image wait for the first to download it.
+        let mut fetched = entry.lock().unwrap();
+        let cached = match *fetched {
+            Some(Fetched::Failed) => return None,
+            Some(Fetched::Image(hash)) => self
+                .by_hash
+                .lock()
+                .unwrap()
+                .get(&hash)
+                .and_then(Weak::upgrade),
+            None => None,
+        };
+        let artwork = match cached {
+            Some(artwork) => artwork,
+            None => match fetch(url) {
+                Ok(artwork) => {
+                    let (hash, artwork) = self.deduplicate(artwork);
+                    *fetched = Some(Fetched::Image(hash));
+                    artwork
+                }
+                Err(e) => {
+                    log::warn!("Failed to download artwork {:?}: {e:#}", url.as_str());
+                    *fetched = Some(Fetched::Failed);
+                    return None;
+                }
+            },
+        };
+        self.keep_recent(&artwork);
+        Some(artwork)
+    }
+
+    /// Share the copy of an image with the same contents, if there is one in
+    /// memory, returning it with the hash of its contents.
+    fn deduplicate(&self, artwork: Artwork) -> ([u8; 32], Arc<Artwork>) {
+        let hash = Sha256::digest(&artwork.data).into();
+        let mut by_hash = self.by_hash.lock().unwrap();
+        if let Some(existing) = by_hash.get(&hash).and_then(Weak::upgrade) {
+            return (hash, existing);
+        }
+        by_hash.retain(|_, image| image.strong_count() > 0);
+        let artwork = Arc::new(artwork);
+        by_hash.insert(hash, Arc::downgrade(&artwork));
+        (hash, artwork)
+    }
+
+    /// Keep the image in memory as one of the most recently used.
+    fn keep_recent(&self, artwork: &Arc<Artwork>) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|image| !Arc::ptr_eq(image, artwork));
+        recent.push_front(Arc::clone(artwork));
+        recent.truncate(RECENT_ARTWORK);
+    }
+}
+
+/// Download an image.
+fn fetch(url: &Url) -> anyhow::Result<Artwork> {
+    log::info!("Downloading artwork {:?}", url.as_str());
+    let body = crate::http::agent().get(url.as_str()).call()?.into_body();
+    let data = crate::http::read_body(body, MAX_ARTWORK_SIZE)?;
+    Artwork::new(data)
+}
+
+/// Save the podcast's artwork as `cover.jpg` or `cover.png` in the directory.
+///
+/// The file is only written if its contents have changed, and a cover of the other
+/// type is removed, for when a podcast switches between JPEG and PNG artwork.
+pub(crate) fn save_cover(directory: &Path, artwork: &Artwork) -> anyhow::Result<()> {
+    let path = directory
+        .join(COVER_NAME)
+        .with_extension(artwork.extension());
+    if std::fs::read(&path).is_ok_and(|existing| existing == artwork.data) {
+        return Ok(());
+    }
+    std::fs::write(&path, &artwork.data)?;
+    log::info!("Wrote podcast artwork to {:?}", path.to_string_lossy());
+    for extension in ["jpg", "png"] {
+        if extension != artwork.extension() {
+            let _ = std::fs::remove_file(directory.join(COVER_NAME).with_extension(extension));
+        }
+    }
+    Ok(())
+}
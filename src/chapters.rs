@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{Episode, http, tag};
+
+/// A chapter of an episode.
+#[derive(Debug)]
+pub(crate) struct Chapter {
+    pub start: Duration,
+    /// End of the chapter, if the chapters file gives one.
+    pub end: Option<Duration>,
+    pub title: String,
+}
+
+/// A [Podcasting 2.0 chapters file](https://github.com/Podcastindex-org/podcast-namespace/blob/main/docs/examples/chapters/jsonChapters.md).
+///
+/// Only the fields poddl uses are read.
+#[derive(Debug, Deserialize)]
+struct ChaptersFile {
+    chapters: Vec<JsonChapter>,
+}
+
+/// A chapter in a chapters file, with times in seconds.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonChapter {
+    start_time: f64,
+    end_time: Option<f64>,
+    title: Option<String>,
+    /// False for chapters that only change the artwork and aren't listed.
+    toc: Option<bool>,
+}
+
+/// Path of the chapters file saved next to an episode's audio file.
+pub(crate) fn sidecar_path(audio_file: &Path) -> PathBuf {
+    audio_file.with_extension("chapters.json")
+}
+
+/// Download the episode's chapters from its `<podcast:chapters>` file, and embed
+/// them into its audio file, save the file next to it, or both.
+///
+/// Episodes without chapters are skipped.
+pub(crate) fn save_chapters(
+    episode: &Episode,
+    audio_file: &Path,
+    embed: bool,
+    sidecar: bool,
+) -> anyhow::Result<()> {
+    let Some(url) = &episode.chapters else {
+        return Ok(());
+    };
+    let body = http::agent().get(url.as_str()).call()?.into_body();
+    let bytes = http::read_body(body, http::MAX_BODY_SIZE)?;
+    let chapters = parse(&bytes)?;
+    if sidecar {
+        let path = sidecar_path(audio_file);
+        std::fs::write(&path, &bytes)?;
+        log::info!("Saved chapters to {:?}", path.to_string_lossy());
+    }
+    if embed && !chapters.is_empty() {
+        tag::write_chapters(audio_file, &chapters, episode.duration)?;
+    }
+    Ok(())
+}
+
+/// Read the listed chapters from a chapters file, in order of their start times.
+fn parse(bytes: &[u8]) -> anyhow::Result<Vec<Chapter>> {
+    let file: ChaptersFile = serde_json::from_slice(bytes).context("Invalid chapters file")?;
+    let seconds = |s: f64| Duration::try_from_secs_f64(s).ok();
+    let mut chapters: Vec<_> = file
+        .chapters
+        .into_iter()
+        .filter(|c| c.toc != Some(false))
+        .filter_map(|c| {
+            Some(Chapter {
+                start: seconds(c.start_time)?,
+                end: c.end_time.and_then(seconds),
+                title: c.title.unwrap_or_default(),
+            })
+        })
+        .collect();
+    chapters.sort_by_key(|c| c.start);
+    for (number, chapter) in chapters.iter_mut().enumerate() {
+        if chapter.title.trim().is_empty() {
+            chapter.title = format!("Chapter {}", number + 1);
+        }
+    }
+    Ok(chapters)
+}
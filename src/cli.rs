@@ -1,25 +1,115 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Args, Parser};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use jiff::civil::Date;
+use regex::Regex;
+
+use crate::template::FilenameTemplate;
 
 /// poddl: Download audio files from a podcast RSS feed
 ///
-/// Provide the URL or file path (with --file) of an RSS feed, and poddl will download
-/// each episode. Files will be saved in the current directory by default, use the
+/// Provide the URL or file path (with --file) of an RSS, Atom or JSON feed, and poddl will
+/// download each episode. Files will be saved in the current directory by default, use the
 /// -o option to choose another directory.
 ///
 /// Episodes will be saved to files named with the episode date and title, use the
 /// -r|--use-remote-filename option to use the episode filename that appears in the
 /// RSS feed enclosure tag instead.
 ///
+/// Use the -t|--filename-template option for other names, in which {podcast},
+/// {title}, {guid}, {season}, {episode}, {type}, {date}, {remote} and {ext} are replaced with
+/// details of each episode. Numbers can be padded with zeros, as in {episode:03}, and
+/// dates formatted with strftime, as in {date:%Y-%m}. A / separates directories.
+///
+/// Use the --opml option to download every feed in an OPML subscription list, such
+/// as those exported by podcast apps. Each podcast is saved to a subdirectory of the
+/// output directory named after the podcast, and feeds that fail are skipped.
+///
 /// The podcast feed can be written to the output directory with the
 /// -k|--keep-rss-feed option.
 ///
+/// Use the --tag option to write the episode title, podcast title, date, episode
+/// number, description and enclosure URL from the feed into the ID3 tags of
+/// downloaded MP3 files, and the same details except the URL into the metadata
+/// of downloaded M4A and MP4 files. The episode's artwork, or else the podcast's,
+/// is embedded too. Use --cover to also save the podcast's artwork next to the
+/// episodes, where music players look for album art. Each image is downloaded only
+/// once per run, however many episodes use it.
+///
+/// Use the --transcripts option to save the transcript of each episode from its
+/// <podcast:transcript> elements, with the same name as the episode's file and the
+/// transcript's extension: .vtt, .srt, .json or .html. WebVTT is preferred, or
+/// choose another format with eg --transcripts=srt. Episodes without a transcript
+/// in the chosen format get one in another format if they have one.
+///
+/// Use the --chapters option to embed the chapters from each episode's
+/// <podcast:chapters> file into downloaded files, as ID3 CHAP and CTOC frames in
+/// MP3s and Nero chapters in M4A and MP4 files, and --chapters-file to save the
+/// chapters file itself next to the episode.
+///
+/// Choose which episodes to download with the --since, --until, --latest,
+/// --title-match, --title-exclude, --season and --exclude-type options. Seasons,
+/// episode numbers and types come from the feed's itunes and podcast namespace
+/// elements, and episodes of feeds without numbers are numbered in order of
+/// publication.
+///
+/// Delete old episodes with the --keep-latest and --keep-days options. Only files
+/// that poddl downloaded itself are deleted, and older episodes are not downloaded.
+///
+/// Use the --dry-run option to list the files that would be downloaded, and whether
+/// they already exist, without downloading anything. Use --dry-run=json to print
+/// one JSON object per line instead of a table.
+///
+/// Use the --download-archive option to keep a record of downloaded episodes, so they
+/// are not downloaded again even if the files are renamed or moved.
+///
+/// Feeds are only processed if they have changed since the last successful run,
+/// according to the server's ETag and Last-Modified headers, which poddl keeps in
+/// the .poddl directory within the output directory, unless the options choosing
+/// and saving episodes have changed or a downloaded file is missing. Use --refresh
+/// to process the feed regardless.
+///
+/// Failed downloads are retried three times, waiting 2s before the first retry and
+/// twice as long before each retry after that. Use --retries and --retry-backoff to
+/// change this. A download that receives no data for 30s fails and is retried from
+/// where it stopped; use --timeout to change this.
+///
 /// Two episodes are downloaded at a time in separate threads, use the -n|--n-threads
 /// option to change this.
+///
+/// poddl exits with status 3 if some episodes failed to download (or could not be
+/// read from the feed), 4 if every episode failed, and 1 if the feed itself could
+/// not be loaded. Feeds from an OPML file that can't be loaded count as failed
+/// episodes.
+///
+/// Use the sync command to keep the output directory as a mirror of the feed,
+/// replacing episodes whose audio has changed and optionally removing episodes that
+/// are no longer in the feed.
+///
+/// Use the watch command to keep running and download new episodes as they
+/// appear, checking the feed every 30 minutes or as often as --interval says.
+///
+/// Use the export-opml command to list the feeds downloaded into an output
+/// directory as an OPML file, which can be imported by podcast apps.
 #[derive(Debug, Parser)]
+#[command(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    disable_help_subcommand = true
+)]
 pub(crate) struct CliArgs {
-    /// URL of RSS feed or path to saved XML file.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+/// Options for downloading a feed's episodes, shared by the sync command.
+#[derive(Debug, Args)]
+pub(crate) struct DownloadArgs {
+    /// URL of RSS feed, path to saved XML file, or OPML file of feeds.
     #[command(flatten)]
     pub input: InputArgs,
 
@@ -31,13 +121,242 @@ pub(crate) struct CliArgs {
     #[arg(short = 'r', long, default_value = "false")]
     pub use_remote_filename: bool,
 
-    /// Save the RSS feed to the output directory.
+    /// Template for output file paths, eg "{podcast}/{date:%Y}/{episode:03} - {title}.{ext}".
+    #[arg(
+        short = 't',
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with = "use_remote_filename"
+    )]
+    pub filename_template: Option<FilenameTemplate>,
+
+    /// Save the RSS (or Atom or JSON) feed to the output directory.
     #[arg(short, long, default_value = "false")]
     pub keep_rss_feed: bool,
 
+    /// Write tags describing each episode from the feed, and its artwork, into
+    /// downloaded MP3 and MP4 files.
+    #[arg(long)]
+    pub tag: bool,
+
+    /// Save the podcast's artwork as cover.jpg (or cover.png) in the output directory.
+    #[arg(long)]
+    pub cover: bool,
+
+    /// Save each episode's transcript next to it, in this format if available.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "vtt",
+        require_equals = true
+    )]
+    pub transcripts: Option<TranscriptFormat>,
+
+    /// Embed each episode's chapters into downloaded MP3 and MP4 files.
+    #[arg(long)]
+    pub chapters: bool,
+
+    /// Save each episode's chapters file next to it, as .chapters.json.
+    #[arg(long)]
+    pub chapters_file: bool,
+
     /// Number of threads to use to download episodes concurrently.
     #[arg(short, long, default_value = "2")]
     pub n_threads: usize,
+
+    /// Download the feed even if the server reports it is unchanged since the last run.
+    #[arg(long, default_value = "false")]
+    pub refresh: bool,
+
+    /// Number of times to retry a failed download.
+    #[arg(long, value_name = "N", default_value = "3")]
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled for each retry after (eg 2s, 500ms, 1m).
+    #[arg(long, value_name = "DURATION", default_value = "2s", value_parser = parse_duration)]
+    pub retry_backoff: Duration,
+
+    /// Give up on a server that takes longer than this to connect, to respond, or to
+    /// send more of an episode.
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    pub timeout: Duration,
+
+    /// List the planned downloads and whether each file exists, without downloading.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "table",
+        require_equals = true
+    )]
+    pub dry_run: Option<PlanFormat>,
+
+    /// Record downloaded episodes in this file, and skip episodes already recorded.
+    #[arg(long, value_name = "FILE")]
+    pub download_archive: Option<PathBuf>,
+
+    /// Options to choose which episodes are downloaded.
+    #[command(flatten)]
+    pub selection: SelectionArgs,
+
+    /// Options to delete old episodes.
+    #[command(flatten)]
+    pub retention: RetentionArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Mirror a feed in the output directory, updating episodes that have changed.
+    ///
+    /// The episodes downloaded by previous runs are remembered in the .poddl
+    /// directory within the output directory. Only episodes that are new, or whose
+    /// enclosure URL or length has changed since they were downloaded, are
+    /// downloaded. Changed episodes replace the earlier file. Use --retire to also
+    /// delete the files of episodes that are no longer in the feed.
+    Sync {
+        #[command(flatten)]
+        download: Box<DownloadArgs>,
+
+        #[command(flatten)]
+        options: SyncOptions,
+    },
+    /// Keep running, checking the feed at an interval and downloading new episodes.
+    ///
+    /// The feed is checked every 30 minutes by default, with each wait randomly
+    /// lengthened or shortened by up to 10%. SIGINT (Ctrl-C) or SIGTERM stops poddl
+    /// once any downloads in progress have finished, and a second signal stops it
+    /// immediately, leaving partial downloads to be resumed by the next run.
+    Watch {
+        #[command(flatten)]
+        download: Box<DownloadArgs>,
+
+        #[command(flatten)]
+        options: WatchOptions,
+    },
+    /// Write an OPML file listing every feed downloaded into an output directory.
+    ExportOpml(ExportOpmlArgs),
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct WatchOptions {
+    /// Time to wait between checks of the feed, eg "30m" or "1h".
+    #[arg(long, default_value = "30m", value_parser = parse_duration)]
+    pub interval: Duration,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct SyncOptions {
+    /// Delete downloaded episodes that are no longer in the feed.
+    #[arg(long)]
+    pub retire: bool,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct ExportOpmlArgs {
+    /// Output directory the feeds were downloaded into.
+    #[arg(short, long = "output-dir", default_value = ".")]
+    pub output_directory: PathBuf,
+
+    /// File to write the OPML to, instead of standard output.
+    pub file: Option<PathBuf>,
+}
+
+/// Parse a non-negative duration such as "2s", "1m 30s" or "PT5M".
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let duration: jiff::SignedDuration = s.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from(duration).map_err(|_| String::from("duration must not be negative"))
+}
+
+/// Output format for the --dry-run listing.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum PlanFormat {
+    /// Aligned columns of action, date and output path.
+    Table,
+    /// One JSON object per episode per line.
+    Json,
+}
+
+/// Transcript formats, which are saved with these extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum TranscriptFormat {
+    /// WebVTT captions.
+    Vtt,
+    /// SubRip captions.
+    Srt,
+    /// Podcasting 2.0 JSON transcript.
+    Json,
+    /// HTML transcript.
+    Html,
+}
+
+/// Kinds of episode, from `<itunes:episodeType>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum EpisodeType {
+    /// A regular episode, which is assumed if the feed doesn't say.
+    Full,
+    /// A preview of the podcast or of a season.
+    Trailer,
+    /// Extra content outside the regular episodes.
+    Bonus,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Episode selection")]
+pub(crate) struct SelectionArgs {
+    /// Only download episodes published on or after this date (YYYY-MM-DD).
+    #[arg(long, value_name = "DATE")]
+    pub since: Option<Date>,
+
+    /// Only download episodes published on or before this date (YYYY-MM-DD).
+    #[arg(long, value_name = "DATE")]
+    pub until: Option<Date>,
+
+    /// Only download the N most recent of the selected episodes.
+    #[arg(long, value_name = "N")]
+    pub latest: Option<usize>,
+
+    /// Only download episodes with titles matching this regular expression.
+    #[arg(long, value_name = "REGEX")]
+    pub title_match: Option<Regex>,
+
+    /// Skip episodes with titles matching this regular expression.
+    #[arg(long, value_name = "REGEX")]
+    pub title_exclude: Option<Regex>,
+
+    /// Only download episodes of these seasons, eg "3" or "1,2".
+    #[arg(long, value_name = "SEASON", value_delimiter = ',')]
+    pub season: Vec<u32>,
+
+    /// Skip episodes of these types, eg "trailer,bonus".
+    #[arg(long, value_name = "TYPE", value_delimiter = ',')]
+    pub exclude_type: Vec<EpisodeType>,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Retention")]
+pub(crate) struct RetentionArgs {
+    /// Keep only the N most recent episodes, deleting older ones downloaded by poddl.
+    #[arg(long, value_name = "N")]
+    pub keep_latest: Option<usize>,
+
+    /// Keep only episodes published in the last D days, deleting older ones downloaded by poddl.
+    #[arg(long, value_name = "D")]
+    pub keep_days: Option<u32>,
+}
+
+impl DownloadArgs {
+    /// The template for output file paths, from -t or the choice of -r.
+    pub fn filename_template(&self) -> FilenameTemplate {
+        self.filename_template.clone().unwrap_or_else(|| {
+            let template = if self.use_remote_filename {
+                FilenameTemplate::REMOTE
+            } else {
+                FilenameTemplate::DATE_AND_TITLE
+            };
+            template.parse().expect("Built-in templates are valid.")
+        })
+    }
 }
 
 #[derive(Debug, Args)]
@@ -49,4 +368,8 @@ pub(crate) struct InputArgs {
     /// File containing RSS feed.
     #[arg(short, long)]
     pub file: Option<PathBuf>,
+
+    /// OPML file listing podcast feeds to download, each to its own subdirectory.
+    #[arg(long, value_name = "FILE")]
+    pub opml: Option<PathBuf>,
 }
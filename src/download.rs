@@ -0,0 +1,567 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, anyhow};
+
+use crate::Episode;
+use crate::http::{self, IdleTimeoutReader};
+use crate::media::{self, UNKNOWN_EXTENSION};
+
+/// The complete file for an episode.
+#[derive(Debug)]
+pub(crate) enum Saved {
+    /// The episode was downloaded to this file.
+    Downloaded(PathBuf),
+    /// The file already existed, so nothing was downloaded.
+    Existing(PathBuf),
+}
+
+impl Saved {
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            Saved::Downloaded(path) | Saved::Existing(path) => path,
+        }
+    }
+}
+
+/// How failed downloads are retried.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    /// Number of times to retry after the first attempt.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each later retry.
+    pub backoff: Duration,
+}
+
+/// Number of bytes read from the start of a file to identify its type.
+const HEAD_LENGTH: u64 = 64;
+
+/// The longest we will wait when a server's Retry-After header asks for more.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// An HTTP error response to an episode download.
+#[derive(Debug)]
+struct HttpStatusError {
+    status: u16,
+    /// Delay requested by the server's Retry-After header.
+    retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP status {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// An error reading an episode's response body, such as the connection being reset.
+///
+/// Kept apart from errors writing the partial file, which retrying won't fix.
+#[derive(Debug)]
+struct BodyReadError(std::io::Error);
+
+impl std::fmt::Display for BodyReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error reading download: {}", self.0)
+    }
+}
+
+impl std::error::Error for BodyReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Download an episode, retrying transient failures with exponential backoff.
+///
+/// Connection errors, timeouts, 429 Too Many Requests and 5xx server errors are
+/// retried, waiting as long as the server asks with Retry-After if it does. Other
+/// errors, such as 404 Not Found, fail immediately. Retries resume the partial file
+/// left by the failed attempt.
+///
+/// If `replace` is true, an existing output file is replaced by the download rather
+/// than skipped, for when the feed has a new version of the episode.
+pub(crate) fn download_with_retries(
+    episode: &Episode,
+    output_file: &Path,
+    replace: bool,
+    policy: RetryPolicy,
+) -> anyhow::Result<Saved> {
+    let mut backoff = policy.backoff;
+    let mut attempt = 0;
+    loop {
+        match download(episode, output_file, replace) {
+            Err(e) if attempt < policy.retries && is_transient(&e) => {
+                attempt += 1;
+                let delay = e
+                    .downcast_ref::<HttpStatusError>()
+                    .and_then(|e| e.retry_after)
+                    .unwrap_or(backoff)
+                    .min(MAX_RETRY_AFTER);
+                log::warn!(
+                    "Retrying {:?} in {}s (attempt {attempt} of {}) after error: {e}",
+                    episode.title,
+                    delay.as_secs_f32(),
+                    policy.retries,
+                );
+                std::thread::sleep(delay);
+                backoff = backoff.saturating_mul(2);
+            }
+            Err(e) => {
+                remove_empty_partial_file(&partial_file_path(output_file));
+                return Err(e);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Remove a partial file left empty by a download that failed before receiving
+/// any of the episode, unless another download has it locked.
+fn remove_empty_partial_file(part_file: &Path) {
+    if let Ok(file) = File::open(part_file)
+        && file.try_lock().is_ok()
+        && file.metadata().is_ok_and(|m| m.len() == 0)
+    {
+        let _ = std::fs::remove_file(part_file);
+    }
+}
+
+/// Whether a download error may succeed if the download is tried again.
+fn is_transient(error: &anyhow::Error) -> bool {
+    if let Some(e) = error.downcast_ref::<HttpStatusError>() {
+        return e.status == 429 || e.status >= 500;
+    }
+    if let Some(e) = error.downcast_ref::<ureq::Error>() {
+        return matches!(
+            e,
+            ureq::Error::Io(_)
+                | ureq::Error::Timeout(_)
+                | ureq::Error::HostNotFound
+                | ureq::Error::ConnectionFailed
+                | ureq::Error::Protocol(_)
+        );
+    }
+    error.downcast_ref::<BodyReadError>().is_some()
+}
+
+/// Parse the Retry-After header, given in seconds or as an HTTP date.
+fn retry_after(response: &ureq::http::Response<ureq::Body>) -> Option<Duration> {
+    let value = response.headers().get("Retry-After")?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = jiff::fmt::rfc2822::parse(value).ok()?;
+    Duration::try_from(date.timestamp().duration_since(jiff::Timestamp::now())).ok()
+}
+
+/// Download an episode to a file.
+///
+/// The episode is written to a hidden `.part` file alongside the output file, which
+/// is renamed into place only once the download has completed, so a file under its
+/// final name is always complete. A `.part` file left by an interrupted run is
+/// resumed with an HTTP Range request. Servers that ignore the Range header cause
+/// the episode to be downloaded again in full. An existing file under the final
+/// name that is shorter than the episode is resumed in the same way.
+///
+/// A download shorter than the response's Content-Length fails with a transient
+/// error, keeping its partial file to be resumed. A file that doesn't match the
+/// enclosure length in the feed is only logged, as feeds are often wrong about it.
+/// Responses that are not the declared type of media file, such as HTML error
+/// pages, are rejected without being saved.
+///
+/// If the output path has an unknown extension, it is replaced with one detected
+/// from the response.
+fn download(episode: &Episode, output_file: &Path, replace: bool) -> anyhow::Result<Saved> {
+    let mut output_file = output_file.to_owned();
+    if !replace && let Some(existing) = existing_output_file(&output_file) {
+        if !is_truncated(episode, &existing) {
+            log::info!(
+                "Skipping as file already exists: {:?}",
+                existing.to_string_lossy()
+            );
+            return Ok(Saved::Existing(existing));
+        }
+        log::info!(
+            "Existing file is incomplete, resuming it: {:?}",
+            existing.to_string_lossy()
+        );
+        adopt_truncated_file(&existing)?;
+        output_file = existing;
+    }
+    let output_file = output_file.as_path();
+
+    // Filename templates may place episodes in subdirectories.
+    if let Some(parent) = output_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let part_file = partial_file_path(output_file);
+    // Held until the download has been moved into place.
+    let mut file = lock_partial_file(&part_file)?;
+    let resume_from = Some(file.metadata()?.len()).filter(|&length| length > 0);
+    log::info!(
+        "{} {} {:?} to {:?}",
+        if resume_from.is_some() {
+            "Resuming"
+        } else {
+            "Downloading"
+        },
+        episode.date.strftime("%F"),
+        episode.title,
+        output_file.to_string_lossy(),
+    );
+
+    log::debug!("{}", episode.audio_url);
+    let mut request = http::agent().get(episode.audio_url.as_str());
+    if let Some(offset) = resume_from {
+        request = request.header("Range", format!("bytes={offset}-"));
+    }
+    let response = request
+        .config()
+        .http_status_as_error(false)
+        .build()
+        .call()?;
+    match (response.status().as_u16(), resume_from) {
+        // The requested range starts at or beyond the end of the remote file, which
+        // is complete only if it is exactly as long as the partial file.
+        (416, Some(offset)) => {
+            if unsatisfied_range_length(&response) == Some(offset) {
+                return finish_download(&part_file, output_file, None).map(Saved::Downloaded);
+            }
+            log::info!(
+                "Partial file doesn't match the remote file, downloading in full: {:?}",
+                output_file.to_string_lossy()
+            );
+            file.set_len(0)?;
+            drop(file);
+            return download(episode, output_file, replace);
+        }
+        (status, _) if status >= 400 => {
+            return Err(HttpStatusError {
+                status,
+                retry_after: retry_after(&response),
+            }
+            .into());
+        }
+        _ => {}
+    }
+
+    let content_type = response.body().mime_type().map(str::to_owned);
+    let content_length = response.body().content_length();
+    let append = match (resume_from, response.status().as_u16()) {
+        (Some(offset), 206) => {
+            let start = content_range_start(&response)?;
+            if start != offset {
+                return Err(anyhow!(
+                    "Server resumed {:?} from byte {start} instead of {offset}",
+                    episode.title
+                ));
+            }
+            true
+        }
+        (Some(offset), _) => {
+            if content_length == Some(offset) {
+                return finish_download(&part_file, output_file, None).map(Saved::Downloaded);
+            }
+            log::info!(
+                "Server ignored range request, downloading in full: {:?}",
+                output_file.to_string_lossy()
+            );
+            false
+        }
+        (None, _) => false,
+    };
+
+    // Check the start of the file before writing anything, unless resuming.
+    let mut response_content = IdleTimeoutReader::new(response.into_body().into_reader());
+    let mut head = Vec::with_capacity(HEAD_LENGTH as usize);
+    if !append {
+        (&mut response_content)
+            .take(HEAD_LENGTH)
+            .read_to_end(&mut head)
+            .map_err(BodyReadError)?;
+    }
+    media::check_response(content_type.as_deref(), &head, episode.extension())?;
+
+    if !append {
+        file.set_len(0)?;
+    }
+    file.write_all(&head)?;
+    let copied = head.len() as u64 + copy_body(&mut response_content, &mut file)?;
+
+    if let Some(expected) = content_length
+        && copied < expected
+    {
+        return Err(BodyReadError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("download ended after {copied} of {expected} bytes"),
+        ))
+        .into());
+    }
+    if let Some(size) = episode.size {
+        let length = std::fs::metadata(&part_file)?.len();
+        if length != size {
+            log::warn!(
+                "{:?} is {length} bytes but the feed says {size} bytes",
+                episode.title
+            );
+        }
+    }
+
+    finish_download(&part_file, output_file, content_type.as_deref()).map(Saved::Downloaded)
+}
+
+/// Whether an existing output file is shorter than the episode, such as one left by
+/// an interrupted run of an earlier version of poddl, which wrote straight to the
+/// final path.
+///
+/// A file at least as long as the enclosure length in the feed is complete. As
+/// feeds are often wrong about the length, the server is asked for the length of
+/// any other file, and the feed's length is only used if the server doesn't say.
+fn is_truncated(episode: &Episode, existing: &Path) -> bool {
+    let Ok(length) = std::fs::metadata(existing).map(|m| m.len()) else {
+        return false;
+    };
+    if episode.size.is_some_and(|size| length >= size) {
+        return false;
+    }
+    match remote_length(episode) {
+        Some(remote) => length < remote,
+        None => episode.size.is_some_and(|size| length < size),
+    }
+}
+
+/// Length of the episode's file according to a HEAD request, if the server gives one.
+fn remote_length(episode: &Episode) -> Option<u64> {
+    let response = http::agent()
+        .head(episode.audio_url.as_str())
+        .config()
+        .http_status_as_error(false)
+        .build()
+        .call()
+        .inspect_err(|e| log::debug!("HEAD request for {:?} failed: {e}", episode.title))
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response
+        .headers()
+        .get("Content-Length")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Move a truncated output file to its partial file path, so that it is resumed.
+///
+/// If a partial file already exists it is resumed instead, and the truncated file
+/// is removed.
+fn adopt_truncated_file(existing: &Path) -> anyhow::Result<()> {
+    let part_file = partial_file_path(existing);
+    if part_file.exists() {
+        std::fs::remove_file(existing)?;
+    } else {
+        std::fs::rename(existing, &part_file)?;
+    }
+    Ok(())
+}
+
+/// Copy a response body to the partial file, returning the number of bytes copied.
+///
+/// Unlike `std::io::copy`, errors reading the body are kept apart from errors
+/// writing the file, so that only the first are retried.
+fn copy_body(body: &mut impl Read, file: &mut File) -> anyhow::Result<u64> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let length = match body.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(length) => length,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(BodyReadError(e).into()),
+        };
+        file.write_all(&buffer[..length])?;
+        copied += length as u64;
+    }
+}
+
+/// Find an existing complete file for the output path.
+///
+/// When the extension is unknown, a file with the same name and any media file
+/// extension is taken to be the episode, as its extension was detected when it was
+/// downloaded. Files saved alongside it, such as transcripts, are not matched.
+pub(crate) fn existing_output_file(output_file: &Path) -> Option<PathBuf> {
+    if output_file.exists() {
+        return Some(output_file.to_owned());
+    }
+    if output_file.extension()? != UNKNOWN_EXTENSION {
+        return None;
+    }
+    let stem = output_file.file_stem()?;
+    std::fs::read_dir(output_file.parent()?)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|path| {
+            path.file_stem() == Some(stem)
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(media::is_media_extension)
+        })
+}
+
+/// Move a complete download into place, detecting the extension if unknown.
+fn finish_download(
+    part_file: &Path,
+    output_file: &Path,
+    content_type: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let output_file = if output_file.extension() == Some(UNKNOWN_EXTENSION.as_ref()) {
+        let extension = content_type
+            .and_then(media::extension_for_mime_type)
+            .or_else(|| media::sniff_extension(&read_head(part_file)));
+        match extension {
+            Some(extension) => output_file.with_extension(extension),
+            None => {
+                log::warn!(
+                    "Unable to identify file type of {:?}",
+                    output_file.to_string_lossy()
+                );
+                output_file.to_owned()
+            }
+        }
+    } else {
+        output_file.to_owned()
+    };
+    move_into_place(part_file, &output_file)?;
+    Ok(output_file)
+}
+
+/// Read the first bytes of a file, or as many as it has.
+///
+/// Returns an empty buffer if the file can't be read.
+fn read_head(path: &Path) -> Vec<u8> {
+    let mut head = Vec::with_capacity(HEAD_LENGTH as usize);
+    if let Ok(file) = File::open(path) {
+        let _ = file.take(HEAD_LENGTH).read_to_end(&mut head);
+    }
+    head
+}
+
+/// Path of the hidden partial file used while downloading to `output_file`.
+///
+/// Eg "2025-10-21 - Episode.mp3" is downloaded to ".2025-10-21 - Episode.mp3.part"
+pub(crate) fn partial_file_path(output_file: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(output_file.file_name().unwrap_or_default());
+    name.push(".part");
+    output_file.with_file_name(name)
+}
+
+/// Flush a completed partial file to disk and rename it to its final name.
+fn move_into_place(part_file: &Path, output_file: &Path) -> anyhow::Result<()> {
+    File::open(part_file)?.sync_all()?;
+    std::fs::rename(part_file, output_file).with_context(|| {
+        format!(
+            "Failed to rename {:?} to {:?}",
+            part_file.to_string_lossy(),
+            output_file.to_string_lossy()
+        )
+    })
+}
+
+/// Delete partial files that no episode will resume.
+///
+/// Partial files are left behind by interrupted runs, and are only useful if an
+/// episode in the current feed would be downloaded to the same path. The output
+/// directory and every directory containing one of the `keep` files is checked.
+/// Partial files locked by a download in progress, such as by another poddl
+/// process using the same directory, are left alone. Logs but otherwise ignores
+/// any error.
+pub(crate) fn remove_stale_partial_files(output_directory: &Path, keep: &HashSet<PathBuf>) {
+    let directories: HashSet<&Path> = keep
+        .iter()
+        .filter_map(|path| path.parent())
+        .chain([output_directory])
+        .collect();
+    let entries = directories
+        .into_iter()
+        .filter_map(|directory| std::fs::read_dir(directory).ok())
+        .flatten();
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !(name.starts_with('.') && name.ends_with(".part")) || keep.contains(&path) {
+            continue;
+        }
+        // The lock is held while the file is removed.
+        let Some(_file) = File::open(&path)
+            .ok()
+            .filter(|file| file.try_lock().is_ok())
+        else {
+            continue;
+        };
+        match std::fs::remove_file(&path) {
+            Ok(()) => log::info!("Removed stale partial file {:?}", path.to_string_lossy()),
+            Err(e) => log::error!("Failed to remove stale partial file: {e}"),
+        }
+    }
+}
+
+/// Parse the first byte position from a 206 response's Content-Range header.
+///
+/// The header has the form `bytes 500-999/1000`.
+fn content_range_start(response: &ureq::http::Response<ureq::Body>) -> anyhow::Result<u64> {
+    let header = response
+        .headers()
+        .get("Content-Range")
+        .context("Partial content response is missing Content-Range")?
+        .to_str()?;
+    header
+        .strip_prefix("bytes ")
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, _)| start.trim().parse().ok())
+        .with_context(|| format!("Failed to parse Content-Range {header:?}"))
+}
+
+/// Parse the length of the remote file from a 416 response's Content-Range header.
+///
+/// The header has the form `bytes */1000`.
+fn unsatisfied_range_length(response: &ureq::http::Response<ureq::Body>) -> Option<u64> {
+    response
+        .headers()
+        .get("Content-Range")?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes */")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Open a partial file for appending, creating it if needed, and lock it so that
+/// no other thread or process writes to it at the same time.
+///
+/// Fails if the file is already locked, such as by an overlapping run of poddl
+/// downloading the same episode.
+fn lock_partial_file(part_file: &Path) -> anyhow::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(part_file)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(anyhow!(
+            "{:?} is already being downloaded",
+            part_file.to_string_lossy()
+        )),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use rss::{Channel, Guid, Item};
+use serde::Deserialize;
+use url::Url;
+
+use crate::Episode;
+use crate::cli::EpisodeType;
+use crate::summary::{Outcome, Summary};
+
+/// Formats of podcast feed that poddl can read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FeedFormat {
+    /// RSS 2.0, the usual format for podcasts.
+    Rss,
+    /// Atom, with episodes as `<link rel="enclosure">` in each entry.
+    Atom,
+    /// JSON Feed, with episodes as attachments of each item.
+    Json,
+}
+
+impl FeedFormat {
+    /// Detect the format from the response Content-Type, if any, or the document.
+    ///
+    /// JSON is recognised by its Content-Type or a leading `{`, otherwise the name
+    /// of the root element is used. Anything other than an Atom `<feed>` is assumed
+    /// to be RSS.
+    pub(crate) fn detect(bytes: &[u8], content_type: Option<&str>) -> Self {
+        let essence = content_type
+            .and_then(|ct| ct.split(';').next())
+            .map(|essence| essence.trim().to_ascii_lowercase());
+        if essence.is_some_and(|essence| essence.ends_with("/json") || essence.ends_with("+json")) {
+            return FeedFormat::Json;
+        }
+        let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+        if bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+            return FeedFormat::Json;
+        }
+        match root_element(bytes) {
+            Some(b"feed") => FeedFormat::Atom,
+            _ => FeedFormat::Rss,
+        }
+    }
+
+    /// Extension used when saving a copy of the feed.
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "rss",
+            FeedFormat::Atom => "atom",
+            FeedFormat::Json => "json",
+        }
+    }
+}
+
+/// Local name of the first element in an XML document, skipping the XML
+/// declaration, comments and doctype, and any namespace prefix.
+fn root_element(bytes: &[u8]) -> Option<&[u8]> {
+    let mut rest = bytes;
+    loop {
+        let start = rest.iter().position(|&b| b == b'<')?;
+        rest = &rest[start + 1..];
+        if matches!(rest.first(), Some(b'?' | b'!')) {
+            let end = rest.iter().position(|&b| b == b'>')?;
+            rest = &rest[end + 1..];
+            continue;
+        }
+        let end = rest
+            .iter()
+            .position(|b| b.is_ascii_whitespace() || matches!(b, b'>' | b'/'))?;
+        let name = &rest[..end];
+        return Some(match name.iter().position(|&b| b == b':') {
+            Some(colon) => &name[colon + 1..],
+            None => name,
+        });
+    }
+}
+
+/// A podcast feed's title and episodes.
+#[derive(Debug)]
+pub(crate) struct Feed {
+    /// Podcast title
+    pub title: String,
+    /// Format the feed was read from
+    pub format: FeedFormat,
+    /// Podcast artwork, which is also used for episodes without their own
+    pub image: Option<Url>,
+    /// Episodes in the feed
+    pub episodes: Vec<Episode>,
+}
+
+impl Feed {
+    /// Parse a feed in any supported format, with the Content-Type it was served with.
+    ///
+    /// Items that can't be read as episodes are recorded as failures in the summary.
+    pub(crate) fn parse(
+        bytes: &[u8],
+        content_type: Option<&str>,
+        summary: &mut Summary,
+    ) -> anyhow::Result<Self> {
+        let format = FeedFormat::detect(bytes, content_type);
+        let (title, image, mut episodes) = match format {
+            FeedFormat::Rss => {
+                let channel = Channel::read_from(Cursor::new(bytes))?;
+                let episodes = extract_episodes(&channel.items, item_label, summary);
+                let image = channel
+                    .itunes_ext()
+                    .and_then(|i| i.image())
+                    .or(channel.image().map(|i| i.url()))
+                    .map(str::to_owned);
+                (channel.title, image, episodes)
+            }
+            FeedFormat::Atom => {
+                let feed = atom_syndication::Feed::read_from(Cursor::new(bytes))?;
+                let episodes = extract_episodes(feed.entries(), entry_label, summary);
+                let image = feed.logo().or(feed.icon()).map(str::to_owned);
+                (feed.title.value, image, episodes)
+            }
+            FeedFormat::Json => {
+                let feed: JsonFeed = serde_json::from_slice(bytes)?;
+                let episodes = extract_episodes(&feed.items, json_item_label, summary);
+                (feed.title, feed.icon, episodes)
+            }
+        };
+        log::info!("{} episodes in feed", episodes.len());
+        let image: Option<Url> = image.and_then(|i| i.trim().parse().ok());
+        for episode in episodes.iter_mut().filter(|e| e.image.is_none()) {
+            episode.image.clone_from(&image);
+        }
+        infer_episode_numbers(&mut episodes);
+        Ok(Self {
+            title,
+            format,
+            image,
+            episodes,
+        })
+    }
+}
+
+/// Number the episodes in order of publication, if the feed numbers none of them.
+///
+/// Only full episodes are numbered, not trailers or bonus episodes, and each season
+/// is numbered separately.
+fn infer_episode_numbers(episodes: &mut [Episode]) {
+    if episodes.iter().any(|e| e.number.is_some()) {
+        return;
+    }
+    let mut order: Vec<usize> = (0..episodes.len())
+        .filter(|&i| episodes[i].episode_type == EpisodeType::Full)
+        .collect();
+    order.sort_by_key(|&i| (episodes[i].season, episodes[i].date.timestamp()));
+    let mut counts: HashMap<Option<u32>, u32> = HashMap::new();
+    for &i in &order {
+        let count = counts.entry(episodes[i].season).or_default();
+        *count += 1;
+        episodes[i].number = Some(*count);
+    }
+    if !order.is_empty() {
+        log::info!("Feed has no episode numbers, numbering in order of publication");
+    }
+}
+
+/// Identify an RSS item by its title or GUID.
+fn item_label(item: &Item) -> &str {
+    item.title().or(item.guid().map(Guid::value)).unwrap_or("?")
+}
+
+/// Identify an Atom entry by its title.
+fn entry_label(entry: &atom_syndication::Entry) -> &str {
+    entry.title().as_str()
+}
+
+/// Identify a JSON Feed item by its title or ID.
+fn json_item_label(item: &JsonFeedItem) -> &str {
+    item.title.as_deref().unwrap_or(&item.id)
+}
+
+/// Extract episode information from the items of a feed.
+///
+/// Items that can't be read as episodes are logged and recorded as failures in the
+/// summary, using `label` to identify them.
+fn extract_episodes<'a, T>(
+    items: &'a [T],
+    label: fn(&T) -> &str,
+    summary: &mut Summary,
+) -> Vec<Episode>
+where
+    Episode: TryFrom<&'a T, Error = anyhow::Error>,
+{
+    items
+        .iter()
+        .filter_map(|i| {
+            Episode::try_from(i)
+                .inspect_err(|e| {
+                    let item = label(i);
+                    log::error!("Skipping item {item:?}: {e:?}");
+                    summary.record(Outcome::Failed(format!("Item {item:?}: {e:#}")));
+                })
+                .ok()
+        })
+        .collect()
+}
+
+/// A [JSON Feed](https://jsonfeed.org/version/1.1) document.
+///
+/// Only the fields poddl uses are read.
+#[derive(Debug, Deserialize)]
+struct JsonFeed {
+    title: String,
+    /// URL of the feed's square artwork
+    icon: Option<String>,
+    #[serde(default)]
+    items: Vec<JsonFeedItem>,
+}
+
+/// An item in a JSON Feed, whose attachments are its media files.
+#[derive(Debug, Deserialize)]
+pub(crate) struct JsonFeedItem {
+    /// Item ID, which some feeds give as a number.
+    #[serde(deserialize_with = "string_or_number")]
+    pub id: String,
+    pub title: Option<String>,
+    /// RFC 3339 publication date
+    pub date_published: Option<String>,
+    /// RFC 3339 date the item was last modified
+    pub date_modified: Option<String>,
+    pub content_text: Option<String>,
+    pub content_html: Option<String>,
+    pub summary: Option<String>,
+    /// URL of the item's main image
+    pub image: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<JsonFeedAttachment>,
+}
+
+/// A file attached to a JSON Feed item.
+#[derive(Debug, Deserialize)]
+pub(crate) struct JsonFeedAttachment {
+    pub url: String,
+    pub mime_type: String,
+    pub size_in_bytes: Option<u64>,
+    pub duration_in_seconds: Option<f64>,
+}
+
+/// Read a JSON Feed item ID, converting numbers to strings.
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(id) => Ok(id),
+        serde_json::Value::Number(id) => Ok(id.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a string or number ID, found {other}"
+        ))),
+    }
+}
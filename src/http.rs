@@ -0,0 +1,127 @@
+use std::io::Read;
+use std::sync::OnceLock;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use ureq::{Agent, Body};
+
+/// Timeout used if `configure` hasn't been called.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of each chunk read from a response body by `IdleTimeoutReader`.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest response read into memory by default, which is ureq's own default limit.
+pub(crate) const MAX_BODY_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The HTTP agent shared by every request, with the timeout it was configured with.
+static AGENT: OnceLock<(Agent, Duration)> = OnceLock::new();
+
+/// Set the timeout for connecting to servers, waiting for their responses, and
+/// waiting for more of a response body.
+///
+/// Must be called before any request is made, otherwise the default timeout is
+/// used. Later calls have no effect.
+pub(crate) fn configure(timeout: Duration) {
+    let _ = AGENT.set(build(timeout));
+}
+
+/// The HTTP agent for all of poddl's requests.
+pub(crate) fn agent() -> &'static Agent {
+    &AGENT.get_or_init(|| build(DEFAULT_TIMEOUT)).0
+}
+
+/// The configured timeout.
+pub(crate) fn timeout() -> Duration {
+    AGENT.get_or_init(|| build(DEFAULT_TIMEOUT)).1
+}
+
+/// Read a whole response body of up to `limit` bytes, with the idle timeout of
+/// `IdleTimeoutReader`.
+pub(crate) fn read_body(body: Body, limit: u64) -> std::io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    IdleTimeoutReader::new(body.into_with_config().limit(limit).reader())
+        .read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn build(timeout: Duration) -> (Agent, Duration) {
+    let config = Agent::config_builder()
+        .timeout_resolve(Some(timeout))
+        .timeout_connect(Some(timeout))
+        .timeout_send_request(Some(timeout))
+        .timeout_recv_response(Some(timeout))
+        .build();
+    (config.into(), timeout)
+}
+
+/// Reads a response body, failing with a `TimedOut` error if no data arrives for
+/// longer than the timeout.
+///
+/// ureq's only timeout for response bodies limits the time to receive the whole
+/// body, which would cut off long downloads on slow connections. Instead, the body
+/// is read on a separate thread, which is abandoned if the connection stalls. The
+/// thread ends once its read returns, when the operating system gives up on the
+/// connection.
+pub(crate) struct IdleTimeoutReader {
+    chunks: Receiver<std::io::Result<Vec<u8>>>,
+    timeout: Duration,
+    /// The chunk being read from, and the position in it.
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl IdleTimeoutReader {
+    pub(crate) fn new(mut body: impl Read + Send + 'static) -> Self {
+        // A few chunks are read ahead while the last is written to disk.
+        let (sender, chunks) = mpsc::sync_channel(4);
+        std::thread::spawn(move || {
+            loop {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let result = match body.read(&mut chunk) {
+                    Ok(length) => {
+                        chunk.truncate(length);
+                        Ok(chunk)
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                // Stop at the end of the body, after an error, or once the reader
+                // has been dropped.
+                let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+                if sender.send(result).is_err() || done {
+                    break;
+                }
+            }
+        });
+        Self {
+            chunks,
+            timeout: timeout(),
+            chunk: vec![],
+            position: 0,
+        }
+    }
+}
+
+impl Read for IdleTimeoutReader {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.chunk.len() {
+            self.chunk = match self.chunks.recv_timeout(self.timeout) {
+                Ok(chunk) => chunk?,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("no data received for {}s", self.timeout.as_secs_f32()),
+                    ));
+                }
+                // The end of the body has already been read.
+                Err(RecvTimeoutError::Disconnected) => vec![],
+            };
+            self.position = 0;
+        }
+        let length = buffer.len().min(self.chunk.len() - self.position);
+        buffer[..length].copy_from_slice(&self.chunk[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
@@ -1,34 +1,85 @@
-use std::fs::{File, OpenOptions};
-use std::io::Cursor;
+use std::collections::HashSet;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Mutex;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context};
+use anyhow::{Context, anyhow};
 use clap::Parser;
 use jiff::Zoned;
-use rss::{Channel, Guid, Item};
+use rss::{Guid, Item};
+use sha2::{Digest, Sha256};
 use url::Url;
 
-use crate::cli::InputArgs;
+use crate::archive::DownloadArchive;
+use crate::artwork::ArtworkCache;
+use crate::cli::{Command, DownloadArgs, EpisodeType, ExportOpmlArgs, InputArgs, SyncOptions};
+use crate::download::{
+    RetryPolicy, Saved, download_with_retries, partial_file_path, remove_stale_partial_files,
+};
+use crate::feed::{Feed, JsonFeedItem};
+use crate::plan::print_plan;
+use crate::select::select_episodes;
+use crate::state::{EpisodeRecord, FeedState};
+use crate::summary::{Outcome, Summary};
+use crate::transcript::Transcript;
 
+mod archive;
+mod artwork;
+mod chapters;
 mod cli;
+mod download;
+mod feed;
+mod http;
+mod media;
+mod mp4;
+mod opml;
+mod plan;
+mod retention;
+mod select;
+mod state;
+mod summary;
+mod tag;
+mod template;
+mod transcript;
+mod watch;
 
 /// A podcast episode
 ///
-/// This corresponds to a single `<item>` in the podcast RSS feed.
+/// This corresponds to a single `<item>` in the podcast RSS feed, an `<entry>`
+/// in an Atom feed, or an item in a JSON Feed.
 #[derive(Debug)]
 struct Episode {
     /// Podcast episode title
     title: String,
+    /// Item GUID, if the feed provides one
+    guid: Option<String>,
     /// Enclosure audio file URL
     audio_url: Url,
-    /// Size of the audio file in bytes
-    #[allow(dead_code)]
-    size: u64,
+    /// Size of the audio file in bytes, if the feed gives one
+    size: Option<u64>,
     /// Episode publication date
     date: Zoned,
     /// Enclosure mime type, indicates the extension.
     mime_type: String,
+    /// Season number, from `<itunes:season>` or `<podcast:season>`
+    season: Option<u32>,
+    /// Episode number, from `<itunes:episode>` or `<podcast:episode>`, or counted
+    /// in order of publication if the feed doesn't number its episodes
+    number: Option<u32>,
+    /// Whether this is a full, trailer or bonus episode
+    episode_type: EpisodeType,
+    /// Episode description or show notes, if the feed has any
+    description: Option<String>,
+    /// Episode artwork, or else the podcast's artwork, if the feed has any
+    image: Option<Url>,
+    /// Transcripts of the episode, from `<podcast:transcript>`
+    transcripts: Vec<Transcript>,
+    /// URL of the episode's JSON chapters file, from `<podcast:chapters>`
+    chapters: Option<Url>,
+    /// Length of the episode, if the feed gives it
+    duration: Option<Duration>,
 }
 
 impl TryFrom<&Item> for Episode {
@@ -40,47 +91,229 @@ impl TryFrom<&Item> for Episode {
             .or_else(|| item.guid().map(Guid::value))
             .map(sanitize_filename::sanitize)
             .context("Failed to extract item title and GUID.")?;
+        let guid = item.guid().map(|g| g.value().to_owned());
         let enclosure = item.enclosure().context("Missing enclosure")?;
         let audio_url: Url = enclosure.url().parse()?;
-        let size: u64 = enclosure.length.parse()?;
+        // Many feeds leave the length empty or zero, or put nonsense in it.
+        let size = enclosure
+            .length
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&size| size > 0);
         let mime_type = enclosure.mime_type.clone();
         let date = item
             .pub_date()
             .and_then(|pd| jiff::fmt::rfc2822::parse(pd).ok())
             .context("Failed to extract item pub date.")?;
+        let itunes = item.itunes_ext();
+        let season = itunes
+            .and_then(|i| i.season())
+            .and_then(|s| s.trim().parse().ok())
+            .or_else(|| podcast_number(item, "season"));
+        let number = itunes
+            .and_then(|i| i.episode())
+            .and_then(|e| e.trim().parse().ok())
+            .or_else(|| podcast_number(item, "episode"));
+        let episode_type = itunes
+            .and_then(|i| i.episode_type())
+            .map_or(EpisodeType::Full, parse_episode_type);
+        let description = item
+            .description()
+            .or_else(|| itunes.and_then(|i| i.summary()))
+            .map(str::to_owned);
+        let image = itunes.and_then(|i| i.image()).and_then(|i| i.parse().ok());
+        let transcripts = podcast_extensions(item, "transcript")
+            .iter()
+            .filter_map(Transcript::from_extension)
+            .collect();
+        let chapters = podcast_extensions(item, "chapters")
+            .iter()
+            .find_map(|chapters| chapters.attrs().get("url")?.trim().parse().ok());
+        let duration = itunes
+            .and_then(|i| i.duration())
+            .and_then(parse_itunes_duration);
         Ok(Self {
             title,
+            guid,
             audio_url,
             size,
             date,
             mime_type,
+            season,
+            number,
+            episode_type,
+            description,
+            image,
+            transcripts,
+            chapters,
+            duration,
         })
     }
 }
 
+impl TryFrom<&atom_syndication::Entry> for Episode {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: &atom_syndication::Entry) -> Result<Self, Self::Error> {
+        let title = [entry.title().as_str(), entry.id()]
+            .into_iter()
+            .find(|t| !t.is_empty())
+            .map(sanitize_filename::sanitize)
+            .context("Failed to extract entry title and ID.")?;
+        let guid = Some(entry.id().to_owned()).filter(|id| !id.is_empty());
+        let enclosure = entry
+            .links()
+            .iter()
+            .find(|l| l.rel() == "enclosure")
+            .context("Missing enclosure link")?;
+        let audio_url: Url = enclosure.href().parse()?;
+        let size = enclosure
+            .length()
+            .and_then(|l| l.trim().parse().ok())
+            .filter(|&size| size > 0);
+        let mime_type = enclosure.mime_type().unwrap_or_default().to_owned();
+        // Atom dates are RFC 3339, converted to keep the feed's offset as with RSS.
+        let date = entry.published().unwrap_or(entry.updated()).to_rfc2822();
+        let date =
+            jiff::fmt::rfc2822::parse(&date).context("Failed to extract entry published date.")?;
+        let description = entry
+            .summary()
+            .map(|summary| summary.as_str())
+            .or_else(|| entry.content().and_then(|content| content.value()))
+            .map(str::to_owned);
+        Ok(Self {
+            title,
+            guid,
+            audio_url,
+            size,
+            date,
+            mime_type,
+            season: None,
+            number: None,
+            episode_type: EpisodeType::Full,
+            description,
+            // Atom entries have no artwork of their own, only the feed's logo.
+            image: None,
+            transcripts: vec![],
+            chapters: None,
+            duration: None,
+        })
+    }
+}
+
+impl TryFrom<&JsonFeedItem> for Episode {
+    type Error = anyhow::Error;
+
+    fn try_from(item: &JsonFeedItem) -> Result<Self, Self::Error> {
+        let title = [item.title.as_deref().unwrap_or_default(), &item.id]
+            .into_iter()
+            .find(|t| !t.is_empty())
+            .map(sanitize_filename::sanitize)
+            .context("Failed to extract item title and ID.")?;
+        let guid = Some(item.id.clone()).filter(|id| !id.is_empty());
+        let attachment = item.attachments.first().context("Missing attachment")?;
+        let audio_url: Url = attachment.url.parse()?;
+        let size = attachment.size_in_bytes.filter(|&size| size > 0);
+        let mime_type = attachment.mime_type.clone();
+        let date = item
+            .date_published
+            .as_deref()
+            .or(item.date_modified.as_deref())
+            .and_then(|date| parse_rfc3339(date).ok())
+            .context("Failed to extract item published date.")?;
+        let description = [&item.content_text, &item.summary, &item.content_html]
+            .into_iter()
+            .find_map(Clone::clone);
+        let image = item.image.as_deref().and_then(|i| i.parse().ok());
+        let duration = attachment
+            .duration_in_seconds
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+        Ok(Self {
+            title,
+            guid,
+            audio_url,
+            size,
+            date,
+            mime_type,
+            season: None,
+            number: None,
+            episode_type: EpisodeType::Full,
+            description,
+            image,
+            transcripts: vec![],
+            chapters: None,
+            duration,
+        })
+    }
+}
+
+/// The item's Podcasting 2.0 namespace elements with the given name, such as
+/// `<podcast:transcript>`.
+fn podcast_extensions<'a>(item: &'a Item, name: &str) -> &'a [rss::extension::Extension] {
+    item.extensions()
+        .get("podcast")
+        .and_then(|elements| elements.get(name))
+        .map_or(&[], Vec::as_slice)
+}
+
+/// The whole number in the item's Podcasting 2.0 element with the given name, such
+/// as `<podcast:season>`.
+fn podcast_number(item: &Item, name: &str) -> Option<u32> {
+    podcast_extensions(item, name)
+        .first()?
+        .value()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Parse an `<itunes:episodeType>`, treating unknown types as full episodes.
+fn parse_episode_type(episode_type: &str) -> EpisodeType {
+    match episode_type.trim().to_ascii_lowercase().as_str() {
+        "trailer" => EpisodeType::Trailer,
+        "bonus" => EpisodeType::Bonus,
+        _ => EpisodeType::Full,
+    }
+}
+
+/// Parse an `<itunes:duration>`, given in seconds or as `HH:MM:SS` or `MM:SS`.
+fn parse_itunes_duration(duration: &str) -> Option<Duration> {
+    let mut seconds = 0.0;
+    for part in duration.trim().split(':') {
+        seconds = seconds * 60.0 + part.trim().parse::<f64>().ok()?;
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Parse an RFC 3339 date, keeping its offset as the time zone.
+fn parse_rfc3339(date: &str) -> anyhow::Result<Zoned> {
+    use jiff::fmt::temporal::{Pieces, PiecesOffset};
+    use jiff::tz::{Offset, TimeZone};
+
+    let offset = match Pieces::parse(date)?.offset() {
+        Some(PiecesOffset::Numeric(offset)) => offset.offset(),
+        _ => Offset::UTC,
+    };
+    let timestamp: jiff::Timestamp = date.parse()?;
+    Ok(timestamp.to_zoned(TimeZone::fixed(offset)))
+}
+
 impl Episode {
-    /// Produce an extension matching the mime type
-    ///
-    /// [Apple lists] the following supported file formats:
+    /// Identifier used for the episode in the download archive.
     ///
-    /// - M4A: audio/x-m4a
-    /// - MP3: audio/mpeg
-    /// - MOV: video/quicktime
-    /// - MP4: video/mp4
-    /// - M4V: video/x-m4v
-    /// - PDF: application/pdf
+    /// This is the item GUID, or the enclosure URL for items without one.
+    fn archive_id(&self) -> &str {
+        self.guid.as_deref().unwrap_or(self.audio_url.as_str())
+    }
+
+    /// Produce an extension matching the mime type, or the enclosure URL.
     ///
-    /// [Apple lists]: https://help.apple.com/itc/podcasts_connect/#/itcb54353390
-    fn extension(&self) -> &'static str {
-        match self.mime_type.as_ref() {
-            "audio/mpeg" => "mp3",
-            "audio/x-m4a" => "m4a",
-            "video/quicktime" => "mov",
-            "video/mp4" => "mp4",
-            "video/x-m4v" => "m4v",
-            "application/pdf" => "pdf",
-            mt => panic!("unexpected mime time {:?}", mt),
-        }
+    /// Returns None if neither identify the file type, in which case it is
+    /// detected from the response when the episode is downloaded.
+    fn extension(&self) -> Option<&'static str> {
+        media::extension_for_mime_type(&self.mime_type)
+            .or_else(|| media::extension_from_path(self.audio_url.path()))
     }
 
     /// Filename from the RSS feed enclosure.
@@ -92,67 +325,86 @@ impl Episode {
             .map(sanitize_filename::sanitize)
             .unwrap()
     }
+}
 
-    /// Filename composed of the title prefixed with the episode's ISO date.
-    fn filename_with_date_and_title(&self) -> String {
-        // Limit the length of the filename part used by the title.
-        let title = {
-            // 255 is the truncate limit for sanitize_filename, -10 for the date, -3 for the
-            // separator, -4 for the extension.
-            let mut limit: usize = 255 - 10 - 3 - 4;
-            while !self.title.is_char_boundary(limit) {
-                limit -= 1;
-            }
-            self.title.split_at(limit).0
-        };
-
-        format!(
-            "{} - {}.{}",
-            self.date.strftime("%F"),
-            sanitize_filename::sanitize(title),
-            self.extension()
-        )
-    }
-
-    /// Select a filename based on the user's choice.
-    fn filename(&self, use_remote_filename: bool) -> String {
-        if use_remote_filename {
-            self.existing_filename()
-        } else {
-            self.filename_with_date_and_title()
-        }
-    }
+/// Where a feed is read from.
+#[derive(Debug)]
+enum FeedSource {
+    Url(String),
+    File(PathBuf),
 }
 
-/// Read RSS feed bytes from a URL or a file.
-fn load_rss_bytes(input: &InputArgs) -> anyhow::Result<Vec<u8>> {
-    let InputArgs { url, file } = input;
+/// The feeds to process, which are those listed in the OPML file if one is given.
+fn feed_sources(input: &InputArgs) -> anyhow::Result<Vec<FeedSource>> {
+    let InputArgs { url, file, opml } = input;
 
-    let bytes = if let Some(url) = url {
-        let response = ureq::get(url).call()?;
-        response.into_body().read_to_vec()?
+    if let Some(url) = url {
+        Ok(vec![FeedSource::Url(url.clone())])
     } else if let Some(file) = file {
-        std::fs::read(file)?
+        Ok(vec![FeedSource::File(file.clone())])
+    } else if let Some(opml) = opml {
+        let urls = opml::read_feed_urls(opml)?;
+        Ok(urls.into_iter().map(FeedSource::Url).collect())
     } else {
-        unreachable!("Clap should ensure either URL or file is provided.");
+        unreachable!("Clap should ensure a URL, file or OPML file is provided.");
+    }
+}
+
+/// Read RSS feed bytes from a URL or a file, with the Content-Type of the response.
+///
+/// If feed state is provided, the request is made conditional on the feed having
+/// changed since the ETag and Last-Modified headers it records, which are updated
+/// from the response. Returns None if the server reports the feed is unchanged.
+fn load_rss_bytes(
+    source: &FeedSource,
+    state: Option<&mut FeedState>,
+) -> anyhow::Result<Option<(Vec<u8>, Option<String>)>> {
+    let (bytes, content_type) = match source {
+        FeedSource::Url(url) => {
+            let mut request = http::agent().get(url);
+            if let Some(state) = &state {
+                if let Some(etag) = &state.etag {
+                    request = request.header("If-None-Match", etag);
+                }
+                if let Some(last_modified) = &state.last_modified {
+                    request = request.header("If-Modified-Since", last_modified);
+                }
+            }
+            let response = request.call()?;
+            if response.status() == 304 {
+                return Ok(None);
+            }
+            if let Some(state) = state {
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_owned)
+                };
+                state.etag = header("ETag");
+                state.last_modified = header("Last-Modified");
+            }
+            let content_type = response.body().mime_type().map(str::to_owned);
+            // Some feeds are larger than ureq's default 10MB limit.
+            let bytes = http::read_body(response.into_body(), u64::MAX)?;
+            (bytes, content_type)
+        }
+        FeedSource::File(file) => (std::fs::read(file)?, None),
     };
 
-    Ok(bytes)
+    Ok(Some((bytes, content_type)))
 }
 
-/// Extract episode information from the RSS feed.
-fn extract_episodes(channel: &Channel) -> Vec<Episode> {
-    let episodes: Vec<Episode> = channel
-        .items
-        .iter()
-        .filter_map(|i| {
-            Episode::try_from(i)
-                .inspect_err(|e| log::error!("{:?}", e))
-                .ok()
-        })
-        .collect();
-    log::info!("{} episodes in RSS feed", episodes.len());
-    episodes
+/// Identify the feed by its URL, or the absolute path of the feed file.
+fn feed_location(source: &FeedSource) -> String {
+    match source {
+        FeedSource::Url(url) => url.clone(),
+        FeedSource::File(file) => std::fs::canonicalize(file)
+            .unwrap_or_else(|_| file.clone())
+            .to_string_lossy()
+            .into_owned(),
+    }
 }
 
 /// Wrapper around CliArgs::parse that logs the received struct.
@@ -170,7 +422,7 @@ fn enable_info_logs() {
 
 /// Make sure the chosen output directory exists as a directory.
 ///
-/// Creates the directory if it does not already exist.
+/// Creates the directory, and any missing parents, if it does not already exist.
 fn ensure_output_directory(output_directory: &Path) -> anyhow::Result<()> {
     // Something else is already present at output_directory.
     if output_directory.exists() && !output_directory.is_dir() {
@@ -180,93 +432,496 @@ fn ensure_output_directory(output_directory: &Path) -> anyhow::Result<()> {
     }
     // Create the directory if it does not exist.
     if !output_directory.exists() {
-        std::fs::create_dir(output_directory)?;
+        std::fs::create_dir_all(output_directory)?;
     }
     Ok(())
 }
 
-/// Write a date-prefixed RSS feed to the output directory.
+/// Write a date-prefixed copy of the feed to the output directory.
 ///
-/// Logs but otherwise ignores any error.
-fn write_rss_feed(channel_title: &str, output_directory: &Path, rss_bytes: &[u8]) {
+/// Returns the path written to. Logs but otherwise ignores any error.
+fn write_rss_feed(feed: &Feed, output_directory: &Path, rss_bytes: &[u8]) -> Option<PathBuf> {
     // Eg "2025-10-21 - In Our Time.rss"
     let filename = sanitize_filename::sanitize(format!(
-        "{} - {}.rss",
+        "{} - {}.{}",
         jiff::Zoned::now().strftime("%F"),
-        channel_title
+        feed.title,
+        feed.format.extension()
     ));
     let path = output_directory.join(filename);
     match std::fs::write(&path, rss_bytes) {
-        Ok(()) => log::info!("Wrote RSS feed to {:?}", path.to_string_lossy()),
-        Err(e) => log::error!("Failed to write RSS feed to output directory: {e}"),
-    };
+        Ok(()) => {
+            log::info!("Wrote RSS feed to {:?}", path.to_string_lossy());
+            Some(path)
+        }
+        Err(e) => {
+            log::error!("Failed to write RSS feed to output directory: {e}");
+            None
+        }
+    }
 }
 
-fn main() -> anyhow::Result<()> {
-    enable_info_logs();
-    let args = parse_args();
+/// A feed with episodes queued for download.
+struct QueuedFeed {
+    /// Feed URL or file path, which identifies the feed in the download archive.
+    location: String,
+    /// Podcast title
+    title: String,
+    /// State to save for the feed once its episodes have been downloaded.
+    state: Option<Mutex<FeedState>>,
+    /// Outcomes of the feed's items.
+    summary: Mutex<Summary>,
+    /// Episodes to download.
+    episodes: Vec<QueuedEpisode>,
+}
 
-    let output_directory = args.output_directory.as_path();
-    ensure_output_directory(output_directory)?;
+/// An episode queued for download.
+struct QueuedEpisode {
+    episode: Episode,
+    /// Path to save the episode to.
+    output_file: PathBuf,
+    /// File of an earlier version of the episode, which the download replaces.
+    replaces: Option<PathBuf>,
+}
 
-    let bytes = load_rss_bytes(&args.input)?;
-    let channel = Channel::read_from(Cursor::new(&bytes))?;
-    let episodes = Mutex::new(extract_episodes(&channel));
+/// Load a feed and choose where to save each of its selected episodes.
+///
+/// When syncing, episodes already downloaded are skipped unless their enclosure has
+/// changed, and with --retire the files of episodes no longer in the feed are
+/// deleted. If the feed has not changed since the last run, no episodes are queued.
+/// Returns None if this is a dry run, in which case the plan for the feed is
+/// printed instead.
+fn queue_feed(
+    source: &FeedSource,
+    args: &DownloadArgs,
+    sync: Option<&SyncOptions>,
+    archive: Option<&DownloadArchive>,
+    artwork: &ArtworkCache,
+) -> anyhow::Result<Option<QueuedFeed>> {
+    let output_directory = args.output_directory.as_path();
+    let location = feed_location(source);
+    log::info!("Reading feed {location:?}");
 
-    std::thread::scope(|scope| {
-        if args.keep_rss_feed {
-            scope.spawn(|| write_rss_feed(channel.title(), output_directory, &bytes));
-        }
-        // Create n_threads downloader threads.
-        for _ in 0..args.n_threads {
-            scope.spawn(|| loop {
-                let Some(episode) = episodes.lock().unwrap().pop() else {
-                    break;
-                };
-                // Download file, log but continue on error.
-                let _ = download(episode, output_directory, args.use_remote_filename)
-                    .inspect_err(|e| log::error!("{e}"));
-            });
+    // Dry runs always list the feed's episodes, even if it hasn't changed.
+    let mut state = args.dry_run.is_none().then(|| {
+        let mut state = FeedState::load(output_directory, &location);
+        // An unchanged feed may still have episodes to download if different ones
+        // are now selected or saved differently, or if downloaded files are missing.
+        let options = options_hash(args, sync);
+        if args.refresh
+            || state.options.as_ref() != Some(&options)
+            || !state.files_exist(output_directory)
+        {
+            state.etag = None;
+            state.last_modified = None;
         }
+        state.options = Some(options);
+        state
     });
+    let Some((bytes, content_type)) = load_rss_bytes(source, state.as_mut())? else {
+        log::info!("Feed has not changed since the last run, nothing to download");
+        // Nothing is queued, but the retention policy still ages out old episodes.
+        return Ok(state.map(|state| QueuedFeed {
+            location: location.clone(),
+            title: state.title.clone().unwrap_or(location),
+            state: Some(Mutex::new(state)),
+            summary: Mutex::default(),
+            episodes: vec![],
+        }));
+    };
+    let mut summary = Summary::default();
+    let mut feed = Feed::parse(&bytes, content_type.as_deref(), &mut summary)?;
+    if let Some(state) = &mut state {
+        state.title = Some(feed.title.clone());
+    }
+    let episodes = std::mem::take(&mut feed.episodes);
+    let in_feed: HashSet<String> = episodes.iter().map(|e| e.archive_id().to_owned()).collect();
+    let count = episodes.len();
+    let episodes = select_episodes(episodes, &args.selection);
+    let episodes = retention::select_retained(episodes, &args.retention);
+    for _ in episodes.len()..count {
+        summary.record(Outcome::Filtered);
+    }
 
-    Ok(())
+    // Each podcast in an OPML file is saved to its own subdirectory.
+    let feed_directory = if args.input.opml.is_some() {
+        output_directory.join(podcast_directory_name(&feed.title, &location))
+    } else {
+        output_directory.to_owned()
+    };
+    let template = args.filename_template();
+    // Episodes whose filenames are the same would overwrite each other, so only
+    // the first is downloaded.
+    let mut output_files = HashSet::new();
+    let episodes: Vec<_> = episodes
+        .into_iter()
+        .map(|e| {
+            let output_file = feed_directory.join(template.render(&e, &feed.title));
+            (e, output_file)
+        })
+        .filter(|(e, output_file)| {
+            let first = output_files.insert(output_file.clone());
+            if !first {
+                log::warn!(
+                    "Skipping {:?} as an earlier episode has the same filename: {:?}",
+                    e.title,
+                    output_file.to_string_lossy()
+                );
+                summary.record(Outcome::Duplicate(format!(
+                    "{} {:?}: an earlier episode has the same filename",
+                    e.date.strftime("%F"),
+                    e.title
+                )));
+            }
+            first
+        })
+        .collect();
+
+    if let Some(format) = args.dry_run {
+        let archive = archive.map(|archive| (archive, location.as_str()));
+        print_plan(&episodes, archive, format);
+        return Ok(None);
+    }
+
+    ensure_output_directory(&feed_directory)?;
+    if args.keep_rss_feed
+        && let Some(path) = write_rss_feed(&feed, &feed_directory, &bytes)
+        && let Some(state) = &mut state
+    {
+        state.snapshot = path.strip_prefix(output_directory).ok().map(Path::to_owned);
+    }
+    if args.cover
+        && let Some(cover) = feed.image.as_ref().and_then(|url| artwork.get(url))
+    {
+        let _ = artwork::save_cover(&feed_directory, &cover)
+            .inspect_err(|e| log::error!("Failed to save podcast artwork: {e}"));
+    }
+
+    let mut episodes: Vec<_> = episodes
+        .into_iter()
+        .map(|(episode, output_file)| QueuedEpisode {
+            episode,
+            output_file,
+            replaces: None,
+        })
+        .collect();
+    if let (Some(sync), Some(state)) = (sync, &mut state) {
+        episodes.retain_mut(|queued| {
+            let Some(record) = state.episode(queued.episode.archive_id()) else {
+                return true;
+            };
+            let recorded_file = output_directory.join(&record.path);
+            if record.enclosure_changed(&queued.episode) {
+                log::info!("Enclosure has changed: {:?}", queued.episode.title);
+                // A partial file would be of the earlier version.
+                let _ = std::fs::remove_file(partial_file_path(&queued.output_file));
+                queued.replaces = Some(recorded_file);
+                true
+            } else if recorded_file.exists() {
+                summary.record(Outcome::Existing);
+                false
+            } else {
+                true
+            }
+        });
+        if sync.retire {
+            retention::retire(state, &in_feed, output_directory, &mut summary);
+        }
+    }
+    if let Some(archive) = archive {
+        episodes.retain(|queued| {
+            // Changed episodes are downloaded again despite being in the archive.
+            let recorded =
+                queued.replaces.is_none() && archive.contains(&location, &queued.episode);
+            if recorded {
+                log::info!(
+                    "Skipping as recorded in download archive: {:?}",
+                    queued.episode.title
+                );
+                summary.record(Outcome::Existing);
+            }
+            !recorded
+        });
+    }
+
+    let resumable: HashSet<_> = episodes
+        .iter()
+        .map(|queued| partial_file_path(&queued.output_file))
+        .collect();
+    remove_stale_partial_files(&feed_directory, &resumable);
+
+    Ok(Some(QueuedFeed {
+        location,
+        title: feed.title,
+        state: state.map(Mutex::new),
+        summary: Mutex::new(summary),
+        episodes,
+    }))
 }
 
-/// Download an episode to a file.
-fn download(
-    episode: Episode,
-    output_directory: &Path,
-    use_remote_filename: bool,
-) -> anyhow::Result<()> {
-    let output_file = output_directory.join(episode.filename(use_remote_filename));
-    log::info!(
-        "Downloading {} {:?} to {:?}",
-        episode.date.strftime("%F"),
-        episode.title,
-        output_file.to_string_lossy(),
+/// Hash of the options that choose which of a feed's episodes are downloaded, and
+/// how and where they are saved, to tell whether they have changed since the last run.
+fn options_hash(args: &DownloadArgs, sync: Option<&SyncOptions>) -> String {
+    let options = format!(
+        "{:?}",
+        (
+            (
+                &args.selection,
+                &args.retention,
+                &args.download_archive,
+                sync
+            ),
+            (
+                args.use_remote_filename,
+                &args.filename_template,
+                args.input.opml.is_some(),
+            ),
+            (
+                args.keep_rss_feed,
+                args.tag,
+                args.cover,
+                &args.transcripts,
+                args.chapters,
+                args.chapters_file,
+            ),
+        )
     );
-    let Ok(mut file) = open_output_file(&output_file) else {
-        log::info!(
-            "Skipping as file already exists: {:?}",
-            output_file.to_string_lossy()
-        );
-        return Ok(());
-    };
+    let hash = Sha256::digest(options.as_bytes());
+    hash[..8].iter().map(|byte| format!("{byte:02x}")).collect()
+}
 
-    log::debug!("{}", episode.audio_url);
-    let response = ureq::get(episode.audio_url.as_str()).call()?;
-    let mut response_content = response.into_body().into_reader();
-    let _ = std::io::copy(&mut response_content, &mut file)?;
+/// Name of the subdirectory for a podcast, from its title or else its location.
+fn podcast_directory_name(title: &str, location: &str) -> String {
+    [title, location]
+        .into_iter()
+        .map(sanitize_filename::sanitize)
+        .find(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| String::from("_"))
+}
 
+/// List the feeds downloaded into an output directory as an OPML file.
+fn export_opml(args: &ExportOpmlArgs) -> anyhow::Result<()> {
+    let mut feeds = FeedState::load_all(&args.output_directory)?;
+    // Feeds read from files have no URL to subscribe to.
+    feeds.retain(|feed| {
+        Url::parse(&feed.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+    });
+    feeds.sort_by_cached_key(|feed| feed.title.as_deref().unwrap_or(&feed.url).to_lowercase());
+    match &args.file {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {:?}", path.to_string_lossy()))?;
+            let mut file = std::io::BufWriter::new(file);
+            opml::write_feed_list(&feeds, &mut file)?;
+            file.flush()?;
+            log::info!(
+                "Wrote {} feeds to {:?}",
+                feeds.len(),
+                path.to_string_lossy()
+            );
+        }
+        None => opml::write_feed_list(&feeds, &mut std::io::stdout().lock())?,
+    }
     Ok(())
 }
 
-/// Open a new file for writing at the given path.
-fn open_output_file(output_file: &PathBuf) -> anyhow::Result<File> {
-    OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(output_file)
-        .map_err(anyhow::Error::new)
+/// Run poddl, exiting with 3 if some episodes failed and 4 if every episode failed.
+fn main() -> anyhow::Result<ExitCode> {
+    enable_info_logs();
+    let cli = parse_args();
+    match cli.command {
+        Some(Command::ExportOpml(export)) => {
+            export_opml(&export)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Sync { download, options }) => run(&download, Some(&options)),
+        Some(Command::Watch { download, options }) => watch::watch(&download, &options),
+        None => run(&cli.download, None),
+    }
+}
+
+/// Download the episodes of every feed, syncing the output directory if requested.
+///
+/// Returns the exit code for the run, from the summary of the episode outcomes.
+fn run(args: &DownloadArgs, sync: Option<&SyncOptions>) -> anyhow::Result<ExitCode> {
+    http::configure(args.timeout);
+    let output_directory = args.output_directory.as_path();
+    let archive = args
+        .download_archive
+        .as_deref()
+        // A dry run only consults an existing archive and never creates one.
+        .filter(|path| args.dry_run.is_none() || path.exists())
+        .map(DownloadArchive::open)
+        .transpose()?;
+
+    // Episodes of every feed share one queue, so the download threads are kept busy
+    // across feeds. Each episode is queued with the index of its feed.
+    let artwork = ArtworkCache::default();
+    let mut summary = Summary::default();
+    let mut feeds = vec![];
+    let mut queue = vec![];
+    for source in feed_sources(&args.input)? {
+        match queue_feed(&source, args, sync, archive.as_ref(), &artwork) {
+            Ok(Some(mut feed)) => {
+                let index = feeds.len();
+                let episodes = std::mem::take(&mut feed.episodes);
+                queue.extend(episodes.into_iter().map(|queued| (index, queued)));
+                feeds.push(feed);
+            }
+            Ok(None) => {}
+            // Keep going past feeds from an OPML file that can't be loaded.
+            Err(e) if args.input.opml.is_some() => {
+                let location = feed_location(&source);
+                log::error!("Failed to load feed {location:?}: {e:#}");
+                summary.record(Outcome::Failed(format!("Feed {location:?}: {e:#}")));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if args.dry_run.is_some() {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let queue = Mutex::new(queue);
+    let retry_policy = RetryPolicy {
+        retries: args.retries,
+        backoff: args.retry_backoff,
+    };
+
+    std::thread::scope(|scope| {
+        // Create n_threads downloader threads.
+        for _ in 0..args.n_threads {
+            scope.spawn(|| {
+                loop {
+                    // Downloads in progress are finished, but no more are started.
+                    if watch::shutdown_requested() {
+                        break;
+                    }
+                    let Some((index, queued)) = queue.lock().unwrap().pop() else {
+                        break;
+                    };
+                    let QueuedEpisode {
+                        episode,
+                        output_file,
+                        replaces,
+                    } = queued;
+                    let feed = &feeds[index];
+                    // Download file, log but continue on error.
+                    let replace = replaces.is_some();
+                    let saved = match download_with_retries(
+                        &episode,
+                        &output_file,
+                        replace,
+                        retry_policy,
+                    ) {
+                        Ok(saved) => saved,
+                        Err(e) => {
+                            log::error!("Failed to download {:?}: {e}", episode.title);
+                            feed.summary.lock().unwrap().record(Outcome::Failed(format!(
+                                "{} {:?}: {e}",
+                                episode.date.strftime("%F"),
+                                episode.title
+                            )));
+                            continue;
+                        }
+                    };
+                    feed.summary.lock().unwrap().record(match saved {
+                        Saved::Downloaded(_) if replace => Outcome::Updated,
+                        Saved::Downloaded(_) => Outcome::Downloaded,
+                        Saved::Existing(_) => Outcome::Existing,
+                    });
+                    let output_file = saved.path();
+                    // Tag before the download is recorded, so the archive has the hash of
+                    // the tagged file.
+                    if args.tag
+                        && let Saved::Downloaded(_) = saved
+                    {
+                        let cover = episode.image.as_ref().and_then(|url| artwork.get(url));
+                        let _ =
+                            tag::tag_episode(output_file, &episode, &feed.title, cover.as_deref())
+                                .inspect_err(|e| {
+                                    log::error!(
+                                        "Failed to tag {:?}: {e:#}",
+                                        output_file.to_string_lossy()
+                                    )
+                                });
+                    }
+                    if let Some(format) = args.transcripts {
+                        let replace = matches!(saved, Saved::Downloaded(_));
+                        let _ = transcript::save_transcript(&episode, output_file, format, replace)
+                            .inspect_err(|e| {
+                                log::error!(
+                                    "Failed to save transcript of {:?}: {e:#}",
+                                    episode.title
+                                )
+                            });
+                    }
+                    let downloaded = matches!(saved, Saved::Downloaded(_));
+                    let embed_chapters = args.chapters && downloaded;
+                    let save_chapters = args.chapters_file
+                        && (downloaded || !chapters::sidecar_path(output_file).exists());
+                    if embed_chapters || save_chapters {
+                        let _ = chapters::save_chapters(
+                            &episode,
+                            output_file,
+                            embed_chapters,
+                            save_chapters,
+                        )
+                        .inspect_err(|e| {
+                            log::error!("Failed to save chapters of {:?}: {e:#}", episode.title)
+                        });
+                    }
+                    // The new version may have a different name, such as when the
+                    // episode's title has changed.
+                    if let Some(replaced) = replaces
+                        && replaced != output_file
+                    {
+                        let _ = std::fs::remove_file(&replaced).inspect_err(|e| {
+                            log::error!("Failed to remove {:?}: {e}", replaced.to_string_lossy())
+                        });
+                    }
+                    // When syncing, existing files are recorded too, so later syncs can
+                    // update them, but they are marked as not poddl's to delete.
+                    if let Some(state) = &feed.state
+                        && (downloaded || sync.is_some())
+                        && let Ok(path) = output_file.strip_prefix(output_directory)
+                    {
+                        let record = EpisodeRecord::new(&episode, path.to_owned(), !downloaded);
+                        state.lock().unwrap().record_episode(record);
+                    }
+                    if let Some(archive) = &archive {
+                        let _ = archive
+                            .record(&feed.location, &episode, output_file)
+                            .inspect_err(|e| log::error!("Failed to update download archive: {e}"));
+                    }
+                }
+            });
+        }
+    });
+
+    for feed in feeds {
+        let mut feed_summary = feed.summary.into_inner().unwrap();
+        if let Some(state) = feed.state {
+            let mut state = state.into_inner().unwrap();
+            // Only remember the feed's headers if every episode succeeded, otherwise
+            // an unchanged feed would cause the next run to skip the episodes that
+            // failed, or weren't started before shutting down.
+            if !feed_summary.is_success() || watch::shutdown_requested() {
+                state.etag = None;
+                state.last_modified = None;
+            }
+            retention::prune(
+                &mut state,
+                &args.retention,
+                output_directory,
+                &mut feed_summary,
+            );
+            let _ = state
+                .save(output_directory)
+                .inspect_err(|e| log::error!("{e}"));
+        }
+        summary.merge(feed_summary);
+    }
+    summary.log();
+    Ok(summary.exit_code())
 }
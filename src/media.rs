@@ -0,0 +1,281 @@
+use anyhow::bail;
+
+/// Extension used in output paths when an episode's file type is not known until it
+/// has been downloaded. It is replaced once the file type has been detected.
+pub(crate) const UNKNOWN_EXTENSION: &str = "unknown";
+
+/// Mime types seen in podcast feeds and HTTP responses, with their extensions.
+///
+/// [Apple lists] M4A, MP3, MOV, MP4, M4V and PDF as supported, but feeds in the
+/// wild use many other (and non-standard) mime types.
+///
+/// [Apple lists]: https://help.apple.com/itc/podcasts_connect/#/itcb54353390
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("audio/mpeg", "mp3"),
+    ("audio/mp3", "mp3"),
+    ("audio/mpeg3", "mp3"),
+    ("audio/mpg", "mp3"),
+    ("audio/x-mp3", "mp3"),
+    ("audio/x-mpeg", "mp3"),
+    ("audio/x-mpeg-3", "mp3"),
+    ("audio/x-m4a", "m4a"),
+    ("audio/m4a", "m4a"),
+    ("audio/mp4", "m4a"),
+    ("audio/x-mp4", "m4a"),
+    ("audio/mp4a-latm", "m4a"),
+    ("audio/x-m4b", "m4b"),
+    ("audio/aac", "aac"),
+    ("audio/x-aac", "aac"),
+    ("audio/aacp", "aac"),
+    ("audio/ogg", "ogg"),
+    ("audio/vorbis", "ogg"),
+    ("application/ogg", "ogg"),
+    ("audio/opus", "opus"),
+    ("audio/flac", "flac"),
+    ("audio/x-flac", "flac"),
+    ("audio/wav", "wav"),
+    ("audio/wave", "wav"),
+    ("audio/x-wav", "wav"),
+    ("audio/vnd.wave", "wav"),
+    ("audio/webm", "webm"),
+    ("audio/x-ms-wma", "wma"),
+    ("video/quicktime", "mov"),
+    ("video/mp4", "mp4"),
+    ("video/x-m4v", "m4v"),
+    ("video/webm", "webm"),
+    ("application/pdf", "pdf"),
+];
+
+/// Extension for a mime type, ignoring case and any parameters such as charset.
+pub(crate) fn extension_for_mime_type(mime_type: &str) -> Option<&'static str> {
+    let essence = mime_type.split(';').next()?.trim();
+    MIME_TYPES
+        .iter()
+        .find(|(mt, _)| mt.eq_ignore_ascii_case(essence))
+        .map(|(_, ext)| *ext)
+}
+
+/// Extension from the end of a URL path, if it is a known media file extension.
+pub(crate) fn extension_from_path(path: &str) -> Option<&'static str> {
+    let (_, ext) = path.rsplit('/').next()?.rsplit_once('.')?;
+    MIME_TYPES
+        .iter()
+        .map(|(_, known)| *known)
+        .find(|known| known.eq_ignore_ascii_case(ext))
+}
+
+/// Whether an extension is one poddl gives downloaded media files.
+pub(crate) fn is_media_extension(extension: &str) -> bool {
+    MIME_TYPES
+        .iter()
+        .any(|(_, known)| known.eq_ignore_ascii_case(extension))
+}
+
+/// Guess the extension for a file from its first bytes.
+pub(crate) fn sniff_extension(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [b'I', b'D', b'3', ..] => Some("mp3"),
+        // ADTS AAC frame sync, which has the layer bits set to zero.
+        [0xFF, b, ..] if b & 0xF6 == 0xF0 => Some("aac"),
+        // MPEG audio frame sync.
+        [0xFF, b, ..] if b & 0xE0 == 0xE0 => Some("mp3"),
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] => match brand.get(..4) {
+            Some(b"M4A ") => Some("m4a"),
+            Some(b"M4B ") => Some("m4b"),
+            Some(b"M4V ") => Some("m4v"),
+            Some(b"qt  ") => Some("mov"),
+            _ => Some("mp4"),
+        },
+        [b'O', b'g', b'g', b'S', ..] => {
+            let is_opus = bytes.get(28..36) == Some(b"OpusHead");
+            Some(if is_opus { "opus" } else { "ogg" })
+        }
+        [b'f', b'L', b'a', b'C', ..] => Some("flac"),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'A',
+            b'V',
+            b'E',
+            ..,
+        ] => Some("wav"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some("webm"),
+        [b'%', b'P', b'D', b'F', ..] => Some("pdf"),
+        _ => None,
+    }
+}
+
+/// Check that a response is the media file the feed declared, not an error page.
+///
+/// Servers sometimes answer expired or blocked enclosure URLs with a successful
+/// response containing an HTML page. Responses with a text, HTML, XML or JSON
+/// Content-Type are rejected, as are bodies whose first bytes are a document, or a
+/// media type other than the `declared` extension. When resuming a download the
+/// first bytes of the response are from the middle of the file, so `head` should
+/// be empty and only the Content-Type is checked.
+pub(crate) fn check_response(
+    content_type: Option<&str>,
+    head: &[u8],
+    declared: Option<&str>,
+) -> anyhow::Result<()> {
+    if let Some(content_type) = content_type {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        let essence = essence.to_ascii_lowercase();
+        if essence.starts_with("text/")
+            || essence.ends_with("/json")
+            || essence.ends_with("/xml")
+            || essence.ends_with("+xml")
+        {
+            bail!("Server responded with {essence} instead of a media file");
+        }
+    }
+    if let Some(document) = sniff_document(head) {
+        bail!("Server responded with {document} instead of a media file");
+    }
+    if let (Some(declared), Some(sniffed)) = (declared, sniff_extension(head))
+        && family(declared) != family(sniffed)
+    {
+        bail!("Response looks like {sniffed} but the feed says it is {declared}");
+    }
+    Ok(())
+}
+
+/// Identify text documents, which are sent by servers in place of media files.
+fn sniff_document(bytes: &[u8]) -> Option<&'static str> {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace())?;
+    let text = bytes[start..].to_ascii_lowercase();
+    if [&b"<!doctype html"[..], b"<html", b"<head", b"<body"]
+        .iter()
+        .any(|prefix| text.starts_with(prefix))
+    {
+        Some("an HTML page")
+    } else if text.starts_with(b"<?xml") {
+        Some("an XML document")
+    } else if text.starts_with(b"{") {
+        Some("a JSON document")
+    } else {
+        None
+    }
+}
+
+/// Group extensions whose files can't be told apart reliably by their first bytes,
+/// or which feeds commonly use interchangeably.
+fn family(extension: &str) -> &str {
+    match extension {
+        "mp3" | "aac" => "mpeg-audio",
+        "m4a" | "m4b" | "m4v" | "mp4" | "mov" => "mp4",
+        "ogg" | "opus" => "ogg",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of an MP4 file with the given brand.
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        [&[0, 0, 0, 0x18][..], b"ftyp", brand, &[0, 0, 0, 0]].concat()
+    }
+
+    #[test]
+    fn sniffs_mpeg_audio() {
+        assert_eq!(sniff_extension(b"ID3\x04\x00"), Some("mp3"));
+        assert_eq!(sniff_extension(&[0xFF, 0xFB, 0x90, 0x64]), Some("mp3"));
+        assert_eq!(sniff_extension(&[0xFF, 0xF1, 0x50, 0x80]), Some("aac"));
+    }
+
+    #[test]
+    fn sniffs_mp4_brands() {
+        assert_eq!(sniff_extension(&ftyp(b"M4A ")), Some("m4a"));
+        assert_eq!(sniff_extension(&ftyp(b"M4B ")), Some("m4b"));
+        assert_eq!(sniff_extension(&ftyp(b"M4V ")), Some("m4v"));
+        assert_eq!(sniff_extension(&ftyp(b"qt  ")), Some("mov"));
+        assert_eq!(sniff_extension(&ftyp(b"isom")), Some("mp4"));
+    }
+
+    #[test]
+    fn sniffs_other_formats() {
+        let mut opus = b"OggS".to_vec();
+        opus.resize(28, 0);
+        opus.extend(b"OpusHead");
+        assert_eq!(sniff_extension(&opus), Some("opus"));
+        assert_eq!(sniff_extension(b"OggS\x00\x02"), Some("ogg"));
+        assert_eq!(sniff_extension(b"fLaC\x00\x00"), Some("flac"));
+        assert_eq!(
+            sniff_extension(b"RIFF\x24\x08\x00\x00WAVEfmt "),
+            Some("wav")
+        );
+        assert_eq!(sniff_extension(&[0x1A, 0x45, 0xDF, 0xA3]), Some("webm"));
+        assert_eq!(sniff_extension(b"%PDF-1.7"), Some("pdf"));
+    }
+
+    #[test]
+    fn does_not_sniff_unknown_bytes() {
+        assert_eq!(sniff_extension(b""), None);
+        assert_eq!(sniff_extension(b"<html>"), None);
+        assert_eq!(sniff_extension(b"\x00\x01\x02\x03"), None);
+    }
+
+    #[test]
+    fn accepts_media() {
+        assert!(check_response(Some("audio/mpeg"), b"ID3\x04\x00", Some("mp3")).is_ok());
+        assert!(check_response(None, &ftyp(b"M4A "), Some("m4a")).is_ok());
+        // Feeds often use the extensions of one family interchangeably.
+        assert!(check_response(None, &ftyp(b"isom"), Some("m4a")).is_ok());
+        assert!(check_response(None, &[0xFF, 0xF1, 0x50, 0x80], Some("mp3")).is_ok());
+        // Unrecognised bytes are given the benefit of the doubt.
+        assert!(check_response(None, b"\x00\x01\x02\x03", Some("mp3")).is_ok());
+        // When resuming, only the Content-Type is checked.
+        assert!(check_response(Some("application/octet-stream"), b"", Some("mp3")).is_ok());
+    }
+
+    #[test]
+    fn rejects_document_content_types() {
+        for content_type in [
+            "text/html; charset=utf-8",
+            "TEXT/PLAIN",
+            "application/json",
+            "application/xml",
+            "application/rss+xml",
+        ] {
+            assert!(
+                check_response(Some(content_type), b"ID3", Some("mp3")).is_err(),
+                "{content_type} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_document_bodies() {
+        for body in [
+            &b"<!DOCTYPE html><html>"[..],
+            b"\xEF\xBB\xBF  <HTML><body>",
+            b"<?xml version=\"1.0\"?>",
+            b"\n{\"error\": \"not found\"}",
+        ] {
+            assert!(
+                check_response(Some("audio/mpeg"), body, Some("mp3")).is_err(),
+                "{body:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_media_types() {
+        let error = check_response(None, &ftyp(b"M4A "), Some("mp3")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Response looks like m4a but the feed says it is mp3"
+        );
+        assert!(check_response(None, b"%PDF-1.7", Some("mp3")).is_err());
+    }
+}
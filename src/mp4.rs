@@ -0,0 +1,522 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, bail};
+
+use crate::artwork::Artwork;
+use crate::chapters::Chapter;
+
+/// Data type of a metadata item holding UTF-8 text.
+const UTF8: u32 = 1;
+/// Data type of a metadata item whose format is implied by its kind, eg `trkn`.
+const IMPLICIT: u32 = 0;
+/// Data type of a metadata item holding a JPEG image.
+const JPEG: u32 = 13;
+/// Data type of a metadata item holding a PNG image.
+const PNG: u32 = 14;
+
+/// An item in an MP4 file's `moov/udta/meta/ilst` box, such as `©nam` for the
+/// title.
+pub(crate) struct MetadataItem {
+    kind: [u8; 4],
+    data_type: u32,
+    value: Vec<u8>,
+}
+
+impl MetadataItem {
+    /// A text item, such as `©nam` or `desc`.
+    pub(crate) fn text(kind: &[u8; 4], value: &str) -> Self {
+        Self {
+            kind: *kind,
+            data_type: UTF8,
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    /// A `trkn` track number item, without a total number of tracks.
+    pub(crate) fn track(number: u32) -> Self {
+        let number = u16::try_from(number).unwrap_or(u16::MAX);
+        let mut value = vec![0, 0];
+        value.extend(number.to_be_bytes());
+        value.extend([0, 0, 0, 0]);
+        Self {
+            kind: *b"trkn",
+            data_type: IMPLICIT,
+            value,
+        }
+    }
+
+    /// A `covr` cover art item.
+    pub(crate) fn image(artwork: &Artwork) -> Self {
+        Self {
+            kind: *b"covr",
+            data_type: match artwork.mime_type {
+                "image/png" => PNG,
+                _ => JPEG,
+            },
+            value: artwork.data.clone(),
+        }
+    }
+
+    /// The item as an `ilst` child box, containing a `data` box with its value.
+    fn to_box(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 + self.value.len());
+        data.extend(self.data_type.to_be_bytes());
+        // Locale, which is always zero.
+        data.extend([0, 0, 0, 0]);
+        data.extend(&self.value);
+        make_box(&self.kind, &make_box(b"data", &data))
+    }
+}
+
+/// Write metadata items into an MP4 file's `moov/udta/meta/ilst` box.
+///
+/// The items replace any existing items of the same kind, and existing items of
+/// the `remove` kinds are removed. Other items are kept. Missing boxes are created.
+pub(crate) fn write_metadata(
+    path: &Path,
+    items: &[MetadataItem],
+    remove: &[[u8; 4]],
+) -> anyhow::Result<()> {
+    rewrite_moov(path, |moov| update_moov(moov, items, remove))
+}
+
+/// Write chapters into an MP4 file as a Nero `moov/udta/chpl` box, replacing any
+/// chapters of that kind the file already has.
+///
+/// The box holds at most 255 chapters, each with a title of up to 255 bytes, so
+/// any more are left out and longer titles are cut short.
+pub(crate) fn write_chapters(path: &Path, chapters: &[Chapter]) -> anyhow::Result<()> {
+    // Version 1, no flags, and a reserved field.
+    let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0];
+    let chapters = &chapters[..chapters.len().min(usize::from(u8::MAX))];
+    chpl.push(chapters.len() as u8);
+    for chapter in chapters {
+        // Start times are in units of 100 nanoseconds.
+        let start = u64::try_from(chapter.start.as_nanos() / 100).unwrap_or(u64::MAX);
+        chpl.extend(start.to_be_bytes());
+        let mut end = chapter.title.len().min(usize::from(u8::MAX));
+        while !chapter.title.is_char_boundary(end) {
+            end -= 1;
+        }
+        let title = &chapter.title[..end];
+        chpl.push(title.len() as u8);
+        chpl.extend(title.as_bytes());
+    }
+    rewrite_moov(path, |moov| {
+        update_child(moov, b"udta", |udta| {
+            update_child(udta.unwrap_or_default(), b"chpl", |_| Ok(chpl))
+        })
+    })
+}
+
+/// Rewrite an MP4 file with new contents for its `moov` box, from `update`.
+///
+/// As this changes the position of any media data after `moov`, the chunk offsets
+/// in each track are adjusted.
+fn rewrite_moov(
+    path: &Path,
+    update: impl FnOnce(&[u8]) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let (moov_start, moov_end) =
+        find_top_level_box(&mut file, length, b"moov")?.context("File has no moov box")?;
+
+    let mut moov = vec![0; usize::try_from(moov_end - moov_start)?];
+    file.seek(SeekFrom::Start(moov_start))?;
+    file.read_exact(&mut moov)?;
+    let header = header_length(&moov)?;
+    let mut new_moov = make_box(b"moov", &update(&moov[header..])?);
+
+    let delta = new_moov.len() as i64 - moov.len() as i64;
+    if delta != 0 {
+        let header = header_length(&new_moov)?;
+        adjust_chunk_offsets(&mut new_moov[header..], moov_end, delta)?;
+    }
+
+    let temporary = crate::tag::temporary_path(path);
+    let mut output = BufWriter::new(File::create(&temporary)?);
+    file.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut (&mut file).take(moov_start), &mut output)?;
+    output.write_all(&new_moov)?;
+    file.seek(SeekFrom::Start(moov_end))?;
+    std::io::copy(&mut file, &mut output)?;
+    output.into_inner()?.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Find the start and end offsets of a top-level box in the file.
+fn find_top_level_box(
+    file: &mut File,
+    length: u64,
+    kind: &[u8; 4],
+) -> anyhow::Result<Option<(u64, u64)>> {
+    let mut position = 0;
+    while position + 8 <= length {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut header[..8])?;
+        let size = match u32::from_be_bytes(header[..4].try_into()?) {
+            // A 64-bit size follows the type.
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                u64::from_be_bytes(header[8..].try_into()?)
+            }
+            // The box extends to the end of the file.
+            0 => length - position,
+            size => u64::from(size),
+        };
+        let end = match position.checked_add(size) {
+            Some(end) if size >= 8 && end <= length => end,
+            _ => bail!("Invalid size of top-level box at offset {position}"),
+        };
+        if &header[4..8] == kind {
+            return Ok(Some((position, end)));
+        }
+        position = end;
+    }
+    Ok(None)
+}
+
+/// A box within a parent box's contents.
+struct Child {
+    kind: [u8; 4],
+    /// Offset of the box, including its header.
+    start: usize,
+    /// Offset of the box's contents.
+    contents: usize,
+    /// Offset of the end of the box.
+    end: usize,
+}
+
+/// The boxes directly inside a box's contents.
+fn children(bytes: &[u8]) -> anyhow::Result<Vec<Child>> {
+    let mut children = vec![];
+    let mut position = 0;
+    while position + 8 <= bytes.len() {
+        let rest = &bytes[position..];
+        let header = header_length(rest)?;
+        let size = match u32::from_be_bytes(rest[..4].try_into()?) {
+            1 => usize::try_from(u64::from_be_bytes(rest[8..16].try_into()?))?,
+            0 => rest.len(),
+            size => usize::try_from(size)?,
+        };
+        if size < header || size > rest.len() {
+            bail!("Invalid box size at offset {position}");
+        }
+        children.push(Child {
+            kind: rest[4..8].try_into()?,
+            start: position,
+            contents: position + header,
+            end: position + size,
+        });
+        position += size;
+    }
+    Ok(children)
+}
+
+/// Length of the header of the box at the start of `bytes`.
+fn header_length(bytes: &[u8]) -> anyhow::Result<usize> {
+    match bytes
+        .get(..4)
+        .map(|size| u32::from_be_bytes(size.try_into().unwrap()))
+    {
+        Some(1) if bytes.len() >= 16 => Ok(16),
+        Some(1) | None => bail!("Truncated box header"),
+        Some(_) if bytes.len() >= 8 => Ok(8),
+        Some(_) => bail!("Truncated box header"),
+    }
+}
+
+/// Build a box from its type and contents.
+fn make_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    let size = u32::try_from(8 + contents.len()).expect("Metadata boxes are under 4GB");
+    let mut bytes = Vec::with_capacity(8 + contents.len());
+    bytes.extend(size.to_be_bytes());
+    bytes.extend(kind);
+    bytes.extend(contents);
+    bytes
+}
+
+/// Rebuild the contents of a box, replacing the first child of the given type with
+/// the result of `update`, which is given the child's contents, or nothing if the
+/// box has no such child, in which case the new child is added at the end.
+fn update_child(
+    contents: &[u8],
+    kind: &[u8; 4],
+    update: impl FnOnce(Option<&[u8]>) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<Vec<u8>> {
+    let children = children(contents)?;
+    let Some(child) = children.iter().find(|child| &child.kind == kind) else {
+        let mut contents = contents.to_vec();
+        contents.extend(make_box(kind, &update(None)?));
+        return Ok(contents);
+    };
+    let mut updated = contents[..child.start].to_vec();
+    updated.extend(make_box(
+        kind,
+        &update(Some(&contents[child.contents..child.end]))?,
+    ));
+    updated.extend(&contents[child.end..]);
+    Ok(updated)
+}
+
+/// Contents of the `moov` box with the metadata items written into it.
+fn update_moov(moov: &[u8], items: &[MetadataItem], remove: &[[u8; 4]]) -> anyhow::Result<Vec<u8>> {
+    update_child(moov, b"udta", |udta| {
+        update_child(udta.unwrap_or_default(), b"meta", |meta| {
+            update_meta(meta, items, remove)
+        })
+    })
+}
+
+/// Contents of the `meta` box with the metadata items written into its `ilst`.
+fn update_meta(
+    meta: Option<&[u8]>,
+    items: &[MetadataItem],
+    remove: &[[u8; 4]],
+) -> anyhow::Result<Vec<u8>> {
+    // iTunes metadata has a "full box" meta, starting with a version and flags,
+    // while QuickTime's has its child boxes straight away.
+    let (version_and_flags, children) = match meta {
+        Some(meta) if meta.get(4..8) == Some(b"hdlr") => (&[][..], meta),
+        Some(meta) if meta.len() >= 4 => meta.split_at(4),
+        _ => (&[0, 0, 0, 0][..], &[][..]),
+    };
+    let mut children = children.to_vec();
+    if self::children(&children)?
+        .iter()
+        .all(|c| &c.kind != b"hdlr")
+    {
+        // Handler identifying the metadata as the iTunes "mdir" type.
+        let mut hdlr = vec![0; 8];
+        hdlr.extend(b"mdirappl");
+        hdlr.extend([0; 9]);
+        children.splice(..0, make_box(b"hdlr", &hdlr));
+    }
+    let children = update_child(&children, b"ilst", |ilst| {
+        let ilst = ilst.unwrap_or_default();
+        let mut updated = vec![];
+        for item in self::children(ilst)? {
+            let replaced = items.iter().any(|i| i.kind == item.kind) || remove.contains(&item.kind);
+            if !replaced {
+                updated.extend(&ilst[item.start..item.end]);
+            }
+        }
+        for item in items {
+            updated.extend(item.to_box());
+        }
+        Ok(updated)
+    })?;
+    Ok([version_and_flags, &children].concat())
+}
+
+/// Add `delta` to every chunk offset in the `moov` box's tracks that is at or after
+/// `threshold`, the original end of the `moov` box.
+fn adjust_chunk_offsets(moov: &mut [u8], threshold: u64, delta: i64) -> anyhow::Result<()> {
+    for trak in children(moov)?.into_iter().filter(|c| &c.kind == b"trak") {
+        let trak = &mut moov[trak.contents..trak.end];
+        let Some(stbl) = find_path(trak, &[b"mdia", b"minf", b"stbl"])? else {
+            continue;
+        };
+        let stbl = &mut trak[stbl];
+        for table in children(stbl)? {
+            let entry_size = match &table.kind {
+                b"stco" => 4,
+                b"co64" => 8,
+                _ => continue,
+            };
+            // Version and flags, then the number of entries.
+            let entries = &mut stbl[table.contents..table.end];
+            let count = entries
+                .get(4..8)
+                .map(|count| u32::from_be_bytes(count.try_into().unwrap()) as usize)
+                .context("Truncated chunk offset table")?;
+            let table = entries
+                .get_mut(8..8 + count * entry_size)
+                .context("Truncated chunk offset table")?;
+            for entry in table.chunks_exact_mut(entry_size) {
+                let offset = match entry_size {
+                    4 => u64::from(u32::from_be_bytes(entry.try_into()?)),
+                    _ => u64::from_be_bytes(entry.try_into()?),
+                };
+                if offset < threshold {
+                    continue;
+                }
+                let offset = offset
+                    .checked_add_signed(delta)
+                    .context("Invalid chunk offset")?;
+                match entry_size {
+                    4 => entry.copy_from_slice(
+                        &u32::try_from(offset)
+                            .context("Chunk offset too large for stco")?
+                            .to_be_bytes(),
+                    ),
+                    _ => entry.copy_from_slice(&offset.to_be_bytes()),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the contents of a box nested inside `bytes` by the path of box types.
+fn find_path(bytes: &[u8], path: &[&[u8; 4]]) -> anyhow::Result<Option<std::ops::Range<usize>>> {
+    let mut range = 0..bytes.len();
+    for kind in path {
+        let Some(child) = children(&bytes[range.clone()])?
+            .into_iter()
+            .find(|c| &c.kind == *kind)
+        else {
+            return Ok(None);
+        };
+        range = range.start + child.contents..range.start + child.end;
+    }
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const AUDIO: &[u8] = b"audio data";
+
+    /// A track whose sample table has a chunk offset table of the given type.
+    fn trak(table: &[u8; 4], offset: u64) -> Vec<u8> {
+        let mut entries = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        match table {
+            b"stco" => entries.extend(u32::try_from(offset).unwrap().to_be_bytes()),
+            _ => entries.extend(offset.to_be_bytes()),
+        }
+        let stbl = make_box(b"stbl", &make_box(table, &entries));
+        make_box(b"trak", &make_box(b"mdia", &make_box(b"minf", &stbl)))
+    }
+
+    /// An MP4 file with an `stco` and a `co64` track pointing at the media data,
+    /// with `moov` before or after `mdat`, and `udta` as the rest of `moov`.
+    fn mp4_file(moov_first: bool, udta: &[u8]) -> Vec<u8> {
+        let ftyp = make_box(b"ftyp", b"M4A \0\0\0\0");
+        let moov = |offset| {
+            let tracks = [trak(b"stco", offset), trak(b"co64", offset)].concat();
+            make_box(b"moov", &[&tracks, udta].concat())
+        };
+        let mdat = make_box(b"mdat", AUDIO);
+        if moov_first {
+            let offset = (ftyp.len() + moov(0).len() + 8) as u64;
+            [ftyp, moov(offset), mdat].concat()
+        } else {
+            let offset = (ftyp.len() + 8) as u64;
+            [ftyp, mdat, moov(offset)].concat()
+        }
+    }
+
+    /// Write the bytes to a temporary file for the test.
+    fn temporary_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("poddl-{}-{name}.m4a", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    /// Contents of the top-level `moov` box.
+    fn moov(file: &[u8]) -> &[u8] {
+        let range = find_path(file, &[b"moov"]).unwrap().unwrap();
+        &file[range]
+    }
+
+    /// The chunk offset in each track's table.
+    fn chunk_offsets(file: &[u8]) -> Vec<u64> {
+        let moov = moov(file);
+        children(moov)
+            .unwrap()
+            .into_iter()
+            .filter(|c| &c.kind == b"trak")
+            .map(|trak| {
+                let trak = &moov[trak.contents..trak.end];
+                let stbl = &trak[find_path(trak, &[b"mdia", b"minf", b"stbl"])
+                    .unwrap()
+                    .unwrap()];
+                let table = &children(stbl).unwrap()[0];
+                let entry = &stbl[table.contents + 8..table.end];
+                match &table.kind {
+                    b"stco" => u64::from(u32::from_be_bytes(entry.try_into().unwrap())),
+                    _ => u64::from_be_bytes(entry.try_into().unwrap()),
+                }
+            })
+            .collect()
+    }
+
+    /// Write a title item into the file, and return the file's new contents.
+    fn write_title(name: &str, file: &[u8]) -> Vec<u8> {
+        let path = temporary_file(name, file);
+        write_metadata(&path, &[MetadataItem::text(b"\xA9nam", "Title")], &[]).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        written
+    }
+
+    #[test]
+    fn adjusts_chunk_offsets_after_moov() {
+        let written = write_title("moov-first", &mp4_file(true, &[]));
+        let offsets = chunk_offsets(&written);
+        assert_eq!(offsets.len(), 2);
+        for offset in offsets {
+            let offset = usize::try_from(offset).unwrap();
+            assert_eq!(&written[offset..offset + AUDIO.len()], AUDIO);
+        }
+    }
+
+    #[test]
+    fn keeps_chunk_offsets_before_moov() {
+        let file = mp4_file(false, &[]);
+        let written = write_title("moov-last", &file);
+        assert_eq!(chunk_offsets(&written), chunk_offsets(&file));
+        let offset = usize::try_from(chunk_offsets(&written)[0]).unwrap();
+        assert_eq!(&written[offset..offset + AUDIO.len()], AUDIO);
+    }
+
+    #[test]
+    fn writes_into_quicktime_meta() {
+        let items = [
+            MetadataItem::text(b"\xA9nam", "Old title").to_box(),
+            MetadataItem::text(b"\xA9too", "Encoder").to_box(),
+        ];
+        let ilst = make_box(b"ilst", &items.concat());
+        let mut hdlr = vec![0; 8];
+        hdlr.extend(b"mdta");
+        hdlr.extend([0; 13]);
+        let meta = make_box(b"meta", &[make_box(b"hdlr", &hdlr), ilst].concat());
+        let written = write_title("quicktime-meta", &mp4_file(true, &make_box(b"udta", &meta)));
+
+        let moov = moov(&written);
+        let meta = &moov[find_path(moov, &[b"udta", b"meta"]).unwrap().unwrap()];
+        // The meta box is still a QuickTime one, without a version and flags, and
+        // its handler isn't duplicated.
+        let kinds: Vec<_> = children(meta).unwrap().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, [*b"hdlr", *b"ilst"]);
+        let ilst = &meta[find_path(meta, &[b"ilst"]).unwrap().unwrap()];
+        let items: Vec<_> = children(ilst).unwrap().iter().map(|c| c.kind).collect();
+        assert_eq!(items, [*b"\xA9too", *b"\xA9nam"]);
+        assert!(ilst.windows(5).any(|w| w == b"Title"));
+        assert!(!ilst.windows(9).any(|w| w == b"Old title"));
+
+        let offset = usize::try_from(chunk_offsets(&written)[1]).unwrap();
+        assert_eq!(&written[offset..offset + AUDIO.len()], AUDIO);
+    }
+
+    #[test]
+    fn rejects_overflowing_box_size() {
+        // A box whose 64-bit size would take the next position past u64::MAX.
+        let huge = [&[0, 0, 0, 1][..], b"free", &(u64::MAX - 4).to_be_bytes()].concat();
+        let file = [make_box(b"ftyp", b"M4A "), huge].concat();
+        let path = temporary_file("overflow", &file);
+        let mut opened = File::open(&path).unwrap();
+        let length = file.len() as u64;
+        assert!(find_top_level_box(&mut opened, length, b"moov").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+use quick_xml::Reader;
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+
+use crate::state::FeedState;
+
+/// Namespace of the attributes poddl adds to exported outlines.
+const PODDL_NAMESPACE: &str = "https://github.com/robjwells/poddl";
+
+/// Read the feed URLs from an OPML subscription list.
+///
+/// Each `<outline>` with an `xmlUrl` attribute is a feed, at any depth, so outlines
+/// used to group feeds into categories are included.
+pub(crate) fn read_feed_urls(path: &Path) -> anyhow::Result<Vec<String>> {
+    let xml = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read OPML file {:?}", path.to_string_lossy()))?;
+    let mut reader = Reader::from_str(&xml);
+    let mut urls = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"outline" =>
+            {
+                if let Some(url) = element.try_get_attribute("xmlUrl")? {
+                    let url = url.decode_and_unescape_value(&reader)?.trim().to_owned();
+                    if !url.is_empty() && !urls.contains(&url) {
+                        urls.push(url);
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    log::info!("{} feeds in OPML file", urls.len());
+    Ok(urls)
+}
+
+/// Write an OPML 2.0 subscription list of the given feeds.
+///
+/// Each feed is an `<outline>` with its title and URL, which podcast apps can
+/// import. The path of the copy of the feed saved with --keep-rss-feed, relative to
+/// the output directory, is given in a `poddl:snapshot` attribute.
+pub(crate) fn write_feed_list(feeds: &[FeedState], output: &mut impl Write) -> anyhow::Result<()> {
+    let created = jiff::fmt::rfc2822::to_string(&jiff::Zoned::now())?;
+    writeln!(output, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        output,
+        r#"<opml version="2.0" xmlns:poddl="{PODDL_NAMESPACE}">"#
+    )?;
+    writeln!(output, "  <head>")?;
+    writeln!(output, "    <title>poddl feeds</title>")?;
+    writeln!(output, "    <dateCreated>{created}</dateCreated>")?;
+    writeln!(output, "  </head>")?;
+    writeln!(output, "  <body>")?;
+    for feed in feeds {
+        let title = escape(feed.title.as_deref().unwrap_or(&feed.url));
+        let url = escape(&feed.url);
+        write!(
+            output,
+            r#"    <outline type="rss" text="{title}" title="{title}" xmlUrl="{url}""#
+        )?;
+        if let Some(snapshot) = &feed.snapshot {
+            let snapshot = snapshot.to_string_lossy();
+            write!(output, r#" poddl:snapshot="{}""#, escape(&snapshot))?;
+        }
+        writeln!(output, "/>")?;
+    }
+    writeln!(output, "  </body>")?;
+    writeln!(output, "</opml>")?;
+    Ok(())
+}
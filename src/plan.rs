@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use crate::Episode;
+use crate::archive::DownloadArchive;
+use crate::cli::PlanFormat;
+use crate::download::{existing_output_file, partial_file_path};
+
+/// What a download run would do with an episode.
+#[derive(Debug, Clone, Copy)]
+enum PlannedAction {
+    /// The episode would be downloaded.
+    Download,
+    /// A partial download would be resumed.
+    Resume,
+    /// The output file already exists.
+    Exists,
+    /// The episode is recorded in the download archive.
+    Archived,
+}
+
+impl PlannedAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            PlannedAction::Download => "download",
+            PlannedAction::Resume => "resume",
+            PlannedAction::Exists => "exists",
+            PlannedAction::Archived => "archived",
+        }
+    }
+}
+
+/// Print the planned downloads to stdout without fetching any audio.
+///
+/// Each episode is listed with the action a real run would take and the path it
+/// would be saved to, either as an aligned table or as one JSON object per line.
+pub(crate) fn print_plan(
+    episodes: &[(Episode, PathBuf)],
+    archive: Option<(&DownloadArchive, &str)>,
+    format: PlanFormat,
+) {
+    for (episode, output_file) in episodes {
+        let action = if archive.is_some_and(|(archive, feed)| archive.contains(feed, episode)) {
+            PlannedAction::Archived
+        } else if existing_output_file(output_file).is_some() {
+            PlannedAction::Exists
+        } else if partial_file_path(output_file).exists() {
+            PlannedAction::Resume
+        } else {
+            PlannedAction::Download
+        };
+        match format {
+            PlanFormat::Table => println!(
+                "{:<8}  {}  {}",
+                action.as_str(),
+                episode.date.strftime("%F"),
+                output_file.to_string_lossy()
+            ),
+            PlanFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "action": action.as_str(),
+                    "date": episode.date.strftime("%F").to_string(),
+                    "title": episode.title,
+                    "url": episode.audio_url.as_str(),
+                    "path": output_file.to_string_lossy(),
+                    "exists": existing_output_file(output_file).is_some(),
+                })
+            ),
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use jiff::{SignedDuration, Timestamp};
+
+use crate::Episode;
+use crate::cli::RetentionArgs;
+use crate::state::FeedState;
+use crate::summary::{Outcome, Summary};
+
+impl RetentionArgs {
+    /// Publication time of the oldest episodes kept by --keep-days.
+    fn cutoff(&self) -> Option<Timestamp> {
+        self.keep_days.map(|days| {
+            let age = SignedDuration::from_hours(i64::from(days) * 24);
+            Timestamp::now().checked_sub(age).unwrap_or(Timestamp::MIN)
+        })
+    }
+}
+
+/// Filter episodes to those the retention policy would keep.
+///
+/// Episodes that would be deleted straight after being downloaded are skipped, so
+/// they aren't downloaded again on every run.
+pub(crate) fn select_retained(
+    mut episodes: Vec<Episode>,
+    retention: &RetentionArgs,
+) -> Vec<Episode> {
+    if let Some(cutoff) = retention.cutoff() {
+        episodes.retain(|e| e.date.timestamp() >= cutoff);
+    }
+    if let Some(keep) = retention.keep_latest {
+        episodes.sort_by_key(|e| std::cmp::Reverse(e.date.timestamp()));
+        episodes.truncate(keep);
+    }
+    episodes
+}
+
+/// Delete the files of episodes downloaded from the feed that are older than the
+/// retention policy allows, and forget them.
+///
+/// Only files recorded in the feed state as downloaded by poddl are deleted, so
+/// adopted files and episodes recorded without a date are always kept.
+pub(crate) fn prune(
+    state: &mut FeedState,
+    retention: &RetentionArgs,
+    output_directory: &Path,
+    summary: &mut Summary,
+) {
+    if retention.keep_latest.is_none() && retention.keep_days.is_none() {
+        return;
+    }
+    let cutoff = retention.cutoff();
+    let dates: Vec<Timestamp> = state.episodes.iter().filter_map(|r| r.date).collect();
+    // Number of recorded episodes published after the given time.
+    let newer = |date: Timestamp| dates.iter().filter(|&&d| d > date).count();
+
+    state.episodes.retain(|record| {
+        let Some(date) = record.date.filter(|_| !record.adopted) else {
+            return true;
+        };
+        let too_old = cutoff.is_some_and(|cutoff| date < cutoff)
+            || retention
+                .keep_latest
+                .is_some_and(|keep| newer(date) >= keep);
+        if !too_old {
+            return true;
+        }
+        !remove_recorded_file(&record.path, output_directory, "old episode", summary)
+    });
+}
+
+/// Delete the files of downloaded episodes that are no longer in the feed, and
+/// forget them.
+///
+/// Nothing is deleted if any items could not be read from the feed, as their
+/// episodes would look as if they had been removed. Adopted files, which poddl
+/// didn't download, are forgotten but not deleted.
+pub(crate) fn retire(
+    state: &mut FeedState,
+    in_feed: &HashSet<String>,
+    output_directory: &Path,
+    summary: &mut Summary,
+) {
+    if !summary.is_success() {
+        log::warn!("Not removing any episodes as some items could not be read from the feed");
+        return;
+    }
+    state.episodes.retain(|record| {
+        if in_feed.contains(&record.id) {
+            return true;
+        }
+        !record.adopted
+            && !remove_recorded_file(
+                &record.path,
+                output_directory,
+                "episode no longer in feed",
+                summary,
+            )
+    });
+}
+
+/// Delete a file recorded in the feed state, returning whether it is gone.
+///
+/// A file that has already been deleted counts as removed. Failures are logged and
+/// recorded in the summary.
+fn remove_recorded_file(
+    path: &Path,
+    output_directory: &Path,
+    description: &str,
+    summary: &mut Summary,
+) -> bool {
+    let path = output_directory.join(path);
+    match std::fs::remove_file(&path) {
+        Ok(()) => {
+            log::info!("Removed {description}: {:?}", path.to_string_lossy());
+            summary.record(Outcome::Removed);
+            true
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => {
+            log::error!("Failed to remove {:?}: {e}", path.to_string_lossy());
+            summary.record(Outcome::Failed(format!(
+                "Removing {:?}: {e}",
+                path.to_string_lossy()
+            )));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use jiff::tz::TimeZone;
+
+    use super::*;
+    use crate::cli::EpisodeType;
+    use crate::state::EpisodeRecord;
+
+    /// An episode published at the given time.
+    fn episode(id: &str, date: Timestamp) -> Episode {
+        Episode {
+            title: id.to_owned(),
+            guid: Some(id.to_owned()),
+            audio_url: format!("https://example.com/{id}.mp3").parse().unwrap(),
+            size: None,
+            date: date.to_zoned(TimeZone::UTC),
+            mime_type: String::from("audio/mpeg"),
+            season: None,
+            number: None,
+            episode_type: EpisodeType::Full,
+            description: None,
+            image: None,
+            transcripts: vec![],
+            chapters: None,
+            duration: None,
+        }
+    }
+
+    /// The time the given number of days ago, offset by some hours.
+    fn days_ago(days: i64, hours: i64) -> Timestamp {
+        Timestamp::now() - SignedDuration::from_hours(days * 24 + hours)
+    }
+
+    fn retention(keep_latest: Option<usize>, keep_days: Option<u32>) -> RetentionArgs {
+        RetentionArgs {
+            keep_latest,
+            keep_days,
+        }
+    }
+
+    /// An empty directory for the test.
+    fn temporary_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("poddl-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Feed state recording episodes with the given IDs and dates, with a file for
+    /// each in the directory.
+    fn state(directory: &Path, episodes: &[(&str, Option<Timestamp>)]) -> FeedState {
+        let episodes = episodes
+            .iter()
+            .map(|&(id, date)| {
+                let path = PathBuf::from(format!("{id}.mp3"));
+                std::fs::write(directory.join(&path), id).unwrap();
+                EpisodeRecord {
+                    id: id.to_owned(),
+                    url: format!("https://example.com/{id}.mp3"),
+                    size: None,
+                    date,
+                    path,
+                    adopted: false,
+                }
+            })
+            .collect();
+        FeedState {
+            episodes,
+            ..Default::default()
+        }
+    }
+
+    fn recorded_ids(state: &FeedState) -> Vec<&str> {
+        state.episodes.iter().map(|r| r.id.as_str()).collect()
+    }
+
+    fn exists(directory: &Path, id: &str) -> bool {
+        directory.join(format!("{id}.mp3")).exists()
+    }
+
+    fn titles(episodes: &[Episode]) -> Vec<&str> {
+        episodes.iter().map(|e| e.title.as_str()).collect()
+    }
+
+    #[test]
+    fn selects_latest_episodes() {
+        let episodes = || {
+            vec![
+                episode("a", days_ago(3, 0)),
+                episode("c", days_ago(1, 0)),
+                episode("b", days_ago(2, 0)),
+            ]
+        };
+        let kept = select_retained(episodes(), &retention(Some(2), None));
+        assert_eq!(titles(&kept), ["c", "b"]);
+        let kept = select_retained(episodes(), &retention(Some(3), None));
+        assert_eq!(kept.len(), 3);
+        assert!(select_retained(episodes(), &retention(Some(0), None)).is_empty());
+        assert_eq!(select_retained(episodes(), &retention(None, None)).len(), 3);
+    }
+
+    #[test]
+    fn selects_recent_episodes() {
+        let episodes = || {
+            vec![
+                episode("old", days_ago(7, 1)),
+                episode("recent", days_ago(7, -1)),
+                episode("new", days_ago(0, 0)),
+            ]
+        };
+        let kept = select_retained(episodes(), &retention(None, Some(7)));
+        assert_eq!(titles(&kept), ["recent", "new"]);
+        // Both limits apply.
+        let kept = select_retained(episodes(), &retention(Some(1), Some(7)));
+        assert_eq!(titles(&kept), ["new"]);
+    }
+
+    #[test]
+    fn prunes_all_but_latest_episodes() {
+        let directory = temporary_directory("prune-latest");
+        let mut state = state(
+            &directory,
+            &[
+                ("a", Some(days_ago(3, 0))),
+                ("b", Some(days_ago(2, 0))),
+                ("c", Some(days_ago(1, 0))),
+            ],
+        );
+        let mut summary = Summary::default();
+        prune(
+            &mut state,
+            &retention(Some(2), None),
+            &directory,
+            &mut summary,
+        );
+        assert_eq!(recorded_ids(&state), ["b", "c"]);
+        assert!(!exists(&directory, "a"));
+        assert!(exists(&directory, "b") && exists(&directory, "c"));
+        assert!(summary.is_success());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn prunes_old_episodes() {
+        let directory = temporary_directory("prune-days");
+        let mut state = state(
+            &directory,
+            &[
+                ("old", Some(days_ago(7, 1))),
+                ("recent", Some(days_ago(7, -1))),
+            ],
+        );
+        prune(
+            &mut state,
+            &retention(None, Some(7)),
+            &directory,
+            &mut Summary::default(),
+        );
+        assert_eq!(recorded_ids(&state), ["recent"]);
+        assert!(!exists(&directory, "old"));
+        assert!(exists(&directory, "recent"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn keeps_everything_without_a_policy() {
+        let directory = temporary_directory("prune-none");
+        let mut state = state(&directory, &[("old", Some(days_ago(1000, 0)))]);
+        prune(
+            &mut state,
+            &retention(None, None),
+            &directory,
+            &mut Summary::default(),
+        );
+        assert_eq!(recorded_ids(&state), ["old"]);
+        assert!(exists(&directory, "old"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn keeps_undated_and_adopted_episodes() {
+        let directory = temporary_directory("prune-kept");
+        let mut state = state(
+            &directory,
+            &[
+                ("undated", None),
+                ("adopted", Some(days_ago(30, 0))),
+                ("old", Some(days_ago(20, 0))),
+                ("new", Some(days_ago(1, 0))),
+            ],
+        );
+        state.episodes[1].adopted = true;
+        prune(
+            &mut state,
+            &retention(Some(1), Some(7)),
+            &directory,
+            &mut Summary::default(),
+        );
+        assert_eq!(recorded_ids(&state), ["undated", "adopted", "new"]);
+        assert!(exists(&directory, "undated") && exists(&directory, "adopted"));
+        assert!(!exists(&directory, "old"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn only_removes_recorded_files() {
+        let directory = temporary_directory("prune-recorded");
+        let mut state = state(&directory, &[("recorded", Some(days_ago(20, 0)))]);
+        std::fs::write(directory.join("unrecorded.mp3"), "").unwrap();
+        prune(
+            &mut state,
+            &retention(None, Some(7)),
+            &directory,
+            &mut Summary::default(),
+        );
+        assert!(!exists(&directory, "recorded"));
+        assert!(exists(&directory, "unrecorded"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn forgets_missing_files() {
+        let directory = temporary_directory("prune-missing");
+        let mut state = state(&directory, &[("missing", Some(days_ago(20, 0)))]);
+        std::fs::remove_file(directory.join("missing.mp3")).unwrap();
+        let mut summary = Summary::default();
+        prune(
+            &mut state,
+            &retention(None, Some(7)),
+            &directory,
+            &mut summary,
+        );
+        assert!(state.episodes.is_empty());
+        assert!(summary.is_success());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn retires_episodes_no_longer_in_feed() {
+        let directory = temporary_directory("retire");
+        let mut state = state(
+            &directory,
+            &[
+                ("kept", None),
+                ("gone", None),
+                ("adopted", None),
+                ("missing", None),
+            ],
+        );
+        state.episodes[2].adopted = true;
+        std::fs::remove_file(directory.join("missing.mp3")).unwrap();
+        let in_feed = HashSet::from([String::from("kept")]);
+        let mut summary = Summary::default();
+        retire(&mut state, &in_feed, &directory, &mut summary);
+        assert_eq!(recorded_ids(&state), ["kept"]);
+        assert!(exists(&directory, "kept") && exists(&directory, "adopted"));
+        assert!(!exists(&directory, "gone"));
+        assert!(summary.is_success());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn retires_nothing_after_a_failure() {
+        let directory = temporary_directory("retire-failed");
+        let mut state = state(&directory, &[("gone", None)]);
+        let mut summary = Summary::default();
+        summary.record(Outcome::Failed(String::from("unreadable item")));
+        retire(&mut state, &HashSet::new(), &directory, &mut summary);
+        assert_eq!(recorded_ids(&state), ["gone"]);
+        assert!(exists(&directory, "gone"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use std::cmp::Reverse;
+
+use crate::Episode;
+use crate::cli::SelectionArgs;
+
+/// Keep only the episodes chosen by the user's selection options.
+///
+/// The date, title, season and type filters are applied first, then `--latest` keeps the most
+/// recent of the remaining episodes.
+pub(crate) fn select_episodes(
+    mut episodes: Vec<Episode>,
+    selection: &SelectionArgs,
+) -> Vec<Episode> {
+    let SelectionArgs {
+        since,
+        until,
+        latest,
+        title_match,
+        title_exclude,
+        season,
+        exclude_type,
+    } = selection;
+
+    let before = episodes.len();
+    episodes.retain(|e| {
+        let date = e.date.date();
+        since.is_none_or(|since| date >= since)
+            && until.is_none_or(|until| date <= until)
+            && title_match.as_ref().is_none_or(|re| re.is_match(&e.title))
+            && !title_exclude
+                .as_ref()
+                .is_some_and(|re| re.is_match(&e.title))
+            && (season.is_empty() || e.season.is_some_and(|s| season.contains(&s)))
+            && !exclude_type.contains(&e.episode_type)
+    });
+    if let Some(latest) = *latest {
+        episodes.sort_by_key(|e| Reverse(e.date.timestamp()));
+        episodes.truncate(latest);
+    }
+
+    if episodes.len() != before {
+        log::info!("{} of {} episodes selected", episodes.len(), before);
+    }
+    episodes
+}
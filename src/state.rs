@@ -0,0 +1,170 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Episode;
+
+/// Name of the directory in the output directory where poddl keeps its state.
+const STATE_DIRECTORY: &str = ".poddl";
+
+/// What poddl remembers about a feed between runs.
+///
+/// Stored as JSON in the output directory's `.poddl/feeds` directory, in a file
+/// named after a hash of the feed URL or file path. A state file is kept for every feed that
+/// has been downloaded into the output directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct FeedState {
+    /// URL of the feed, or the path of a feed file.
+    pub url: String,
+    /// ETag header from the last response, sent as If-None-Match.
+    pub etag: Option<String>,
+    /// Last-Modified header from the last response, sent as If-Modified-Since.
+    pub last_modified: Option<String>,
+    /// Hash of the options that chose and saved the feed's episodes in the last run.
+    /// The feed is only requested conditionally if they are unchanged.
+    pub options: Option<String>,
+    /// Podcast title from the feed.
+    pub title: Option<String>,
+    /// Copy of the feed saved with --keep-rss-feed, relative to the output directory.
+    pub snapshot: Option<PathBuf>,
+    /// Episodes downloaded from the feed.
+    #[serde(default)]
+    pub episodes: Vec<EpisodeRecord>,
+}
+
+/// An episode file downloaded by poddl, or found in place when syncing, as recorded
+/// in the feed state.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EpisodeRecord {
+    /// Item GUID, or the enclosure URL if it has none.
+    pub id: String,
+    /// Enclosure URL the file was downloaded from.
+    pub url: String,
+    /// Enclosure length given by the feed, if any.
+    pub size: Option<u64>,
+    /// Publication date of the episode.
+    pub date: Option<jiff::Timestamp>,
+    /// Path of the file, relative to the output directory.
+    pub path: PathBuf,
+    /// Whether the file already existed rather than being downloaded by poddl, in
+    /// which case it is never deleted.
+    #[serde(default)]
+    pub adopted: bool,
+}
+
+impl EpisodeRecord {
+    pub(crate) fn new(episode: &Episode, path: PathBuf, adopted: bool) -> Self {
+        Self {
+            id: episode.archive_id().to_owned(),
+            url: episode.audio_url.to_string(),
+            size: episode.size,
+            date: Some(episode.date.timestamp()),
+            path,
+            adopted,
+        }
+    }
+
+    /// Whether the feed's enclosure for the episode differs from the one downloaded.
+    ///
+    /// The enclosure has changed if its URL has, or if the feed gave a length both
+    /// times and it has changed.
+    pub(crate) fn enclosure_changed(&self, episode: &Episode) -> bool {
+        self.url != episode.audio_url.as_str()
+            || matches!((self.size, episode.size), (Some(old), Some(new)) if old != new)
+    }
+}
+
+impl FeedState {
+    /// Load the state for the feed URL, or an empty state if there is none.
+    pub(crate) fn load(output_directory: &Path, url: &str) -> Self {
+        let path = feed_state_path(output_directory, url);
+        std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .inspect_err(|e| log::warn!("Ignoring invalid feed state {path:?}: {e}"))
+                    .ok()
+            })
+            .unwrap_or_else(|| FeedState {
+                url: url.to_owned(),
+                ..Default::default()
+            })
+    }
+
+    /// The record of the episode with the given ID, if it has been downloaded.
+    pub(crate) fn episode(&self, id: &str) -> Option<&EpisodeRecord> {
+        self.episodes.iter().find(|record| record.id == id)
+    }
+
+    /// Whether the file of every recorded episode is still in the output directory.
+    pub(crate) fn files_exist(&self, output_directory: &Path) -> bool {
+        self.episodes
+            .iter()
+            .all(|record| output_directory.join(&record.path).exists())
+    }
+
+    /// Record a downloaded episode, replacing any earlier record of it.
+    pub(crate) fn record_episode(&mut self, record: EpisodeRecord) {
+        match self.episodes.iter_mut().find(|r| r.id == record.id) {
+            Some(existing) => *existing = record,
+            None => self.episodes.push(record),
+        }
+    }
+
+    /// Load the state of every feed downloaded into the output directory.
+    pub(crate) fn load_all(output_directory: &Path) -> anyhow::Result<Vec<Self>> {
+        let directory = state_directory(output_directory).join("feeds");
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to read feed states in {:?}",
+                        directory.to_string_lossy()
+                    )
+                });
+            }
+        };
+        let read = |path: &Path| -> anyhow::Result<Self> {
+            Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+        };
+        let mut states = vec![];
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read(&path) {
+                Ok(state) => states.push(state),
+                Err(e) => log::warn!("Ignoring invalid feed state {path:?}: {e}"),
+            }
+        }
+        Ok(states)
+    }
+
+    /// Save the state for the feed.
+    pub(crate) fn save(&self, output_directory: &Path) -> anyhow::Result<()> {
+        let path = feed_state_path(output_directory, &self.url);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write feed state {:?}", path.to_string_lossy()))
+    }
+}
+
+/// Directory within the output directory where poddl keeps its state.
+fn state_directory(output_directory: &Path) -> PathBuf {
+    output_directory.join(STATE_DIRECTORY)
+}
+
+/// Path of the state file for the feed URL.
+fn feed_state_path(output_directory: &Path, url: &str) -> PathBuf {
+    let hash = Sha256::digest(url.as_bytes());
+    let name: String = hash[..8].iter().map(|byte| format!("{byte:02x}")).collect();
+    state_directory(output_directory)
+        .join("feeds")
+        .join(name + ".json")
+}
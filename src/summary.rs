@@ -0,0 +1,113 @@
+use std::process::ExitCode;
+
+/// What happened to a single item in the feed.
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    /// The episode was downloaded.
+    Downloaded,
+    /// A new version of a downloaded episode replaced the earlier file.
+    Updated,
+    /// The file of an episode no longer in the feed, or older than the retention
+    /// policy allows, was deleted.
+    Removed,
+    /// The episode was skipped as its file exists or it is in the download archive.
+    Existing,
+    /// The episode was not chosen by the selection options.
+    Filtered,
+    /// The episode was skipped as an earlier episode in the feed has the same
+    /// filename.
+    Duplicate(String),
+    /// The item could not be read from the feed, or the episode failed to download.
+    Failed(String),
+}
+
+/// Tally of episode outcomes, reported at the end of a run.
+#[derive(Debug, Default)]
+pub(crate) struct Summary {
+    downloaded: usize,
+    updated: usize,
+    removed: usize,
+    existing: usize,
+    filtered: usize,
+    /// Description of each episode skipped for having a duplicate filename.
+    duplicates: Vec<String>,
+    /// Description of each failure, with the reason.
+    failures: Vec<String>,
+}
+
+impl Summary {
+    /// Exit code when some, but not all, episodes failed.
+    const PARTIAL_FAILURE: u8 = 3;
+    /// Exit code when every episode that was attempted failed.
+    const TOTAL_FAILURE: u8 = 4;
+
+    pub(crate) fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Downloaded => self.downloaded += 1,
+            Outcome::Updated => self.updated += 1,
+            Outcome::Removed => self.removed += 1,
+            Outcome::Existing => self.existing += 1,
+            Outcome::Filtered => self.filtered += 1,
+            Outcome::Duplicate(description) => self.duplicates.push(description),
+            Outcome::Failed(reason) => self.failures.push(reason),
+        }
+    }
+
+    /// Add the outcomes from another summary, such as that of another feed.
+    pub(crate) fn merge(&mut self, other: Summary) {
+        self.downloaded += other.downloaded;
+        self.updated += other.updated;
+        self.removed += other.removed;
+        self.existing += other.existing;
+        self.filtered += other.filtered;
+        self.duplicates.extend(other.duplicates);
+        self.failures.extend(other.failures);
+    }
+
+    /// Log the number of episodes with each outcome, and the reason for each failure.
+    ///
+    /// Updated and removed episodes, which only happen when syncing or pruning old
+    /// episodes, and episodes skipped for duplicate filenames are included if there
+    /// are any.
+    pub(crate) fn log(&self) {
+        let changes = if self.updated + self.removed > 0 {
+            format!(", {} updated, {} removed", self.updated, self.removed)
+        } else {
+            String::new()
+        };
+        let duplicates = if self.duplicates.is_empty() {
+            String::new()
+        } else {
+            format!(", {} skipped as duplicate filenames", self.duplicates.len())
+        };
+        log::info!(
+            "{} downloaded{changes}, {} skipped as already downloaded{duplicates}, {} not selected, {} failed",
+            self.downloaded,
+            self.existing,
+            self.filtered,
+            self.failures.len()
+        );
+        for duplicate in &self.duplicates {
+            log::warn!("  {duplicate}");
+        }
+        for failure in &self.failures {
+            log::error!("  {failure}");
+        }
+    }
+
+    /// Whether no episodes failed.
+    pub(crate) fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Exit code for the run, distinguishing partial and total failure.
+    pub(crate) fn exit_code(&self) -> ExitCode {
+        if self.is_success() {
+            ExitCode::SUCCESS
+        } else if self.downloaded + self.updated + self.existing == 0 {
+            ExitCode::from(Self::TOTAL_FAILURE)
+        } else {
+            ExitCode::from(Self::PARTIAL_FAILURE)
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::Duration;
+
+use id3::frame::{Comment, Picture, PictureType, TableOfContents};
+use id3::{Frame, Tag, TagLike, Version};
+
+use crate::Episode;
+use crate::artwork::Artwork;
+use crate::chapters::Chapter;
+use crate::mp4::{self, MetadataItem};
+
+/// Longest description written to an MP4 file's `desc` item. Longer descriptions
+/// are cut short there, and written in full to the `ldes` item.
+const MP4_SHORT_DESCRIPTION: usize = 255;
+
+/// Write metadata from the feed into a downloaded episode's file.
+///
+/// The `cover` artwork, if any, is embedded as the front cover, replacing the file's
+/// own. Files of types that can't be tagged are left unchanged.
+pub(crate) fn tag_episode(
+    path: &Path,
+    episode: &Episode,
+    podcast: &str,
+    cover: Option<&Artwork>,
+) -> anyhow::Result<()> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("mp3") => write_id3(path, episode, podcast, cover),
+        Some("m4a" | "m4b" | "m4v" | "mp4") => write_mp4(path, episode, podcast, cover),
+        _ => Ok(()),
+    }
+}
+
+/// Write ID3v2.4 frames from the feed into an MP3 file.
+///
+/// The frames replace any the file already has, while the file's other frames are
+/// kept. If the file's tag can't be read, a new tag is put in front of it.
+fn write_id3(
+    path: &Path,
+    episode: &Episode,
+    podcast: &str,
+    cover: Option<&Artwork>,
+) -> anyhow::Result<()> {
+    let (mut tag, readable) = read_id3(path);
+    tag.set_title(&episode.title);
+    tag.set_album(podcast);
+    tag.set_artist(podcast);
+    let date = episode.date.datetime();
+    tag.set_date_released(id3::Timestamp {
+        year: i32::from(date.year()),
+        month: u8::try_from(date.month()).ok(),
+        day: u8::try_from(date.day()).ok(),
+        hour: u8::try_from(date.hour()).ok(),
+        minute: u8::try_from(date.minute()).ok(),
+        second: u8::try_from(date.second()).ok(),
+    });
+    match episode.number {
+        Some(number) => tag.set_track(number),
+        None => tag.remove_track(),
+    }
+    tag.remove_comment(None, None);
+    if let Some(description) = &episode.description {
+        tag.add_frame(Comment {
+            // The feed's language isn't known, so use ISO 639-2's "undetermined".
+            lang: String::from("und"),
+            description: String::new(),
+            text: description.clone(),
+        });
+    }
+    tag.add_frame(Frame::link("WOAS", episode.audio_url.as_str()));
+    if let Some(cover) = cover {
+        tag.remove_picture_by_type(PictureType::CoverFront);
+        tag.add_frame(Picture {
+            mime_type: cover.mime_type.to_owned(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: cover.data.clone(),
+        });
+    }
+    save_id3(path, &tag, readable)
+}
+
+/// Write chapters into a downloaded episode's file, replacing any it already has.
+///
+/// A chapter without an end time ends where the next one starts, and the last
+/// ends with the episode, if its duration is known. Files of types that can't
+/// hold chapters are left unchanged.
+pub(crate) fn write_chapters(
+    path: &Path,
+    chapters: &[Chapter],
+    duration: Option<Duration>,
+) -> anyhow::Result<()> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("mp3") => write_id3_chapters(path, chapters, duration),
+        Some("m4a" | "m4b" | "m4v" | "mp4") => mp4::write_chapters(path, chapters),
+        _ => Ok(()),
+    }
+}
+
+/// Write chapters into an MP3 file as ID3v2 `CHAP` frames, listed in order by a
+/// top-level `CTOC` frame.
+fn write_id3_chapters(
+    path: &Path,
+    chapters: &[Chapter],
+    duration: Option<Duration>,
+) -> anyhow::Result<()> {
+    let milliseconds = |d: Duration| u32::try_from(d.as_millis()).unwrap_or(u32::MAX);
+    let (mut tag, readable) = read_id3(path);
+    tag.remove_all_chapters();
+    tag.remove_all_tables_of_contents();
+    let mut elements = vec![];
+    for (index, chapter) in chapters.iter().enumerate() {
+        let end = chapter
+            .end
+            .or(chapters.get(index + 1).map(|next| next.start))
+            .or(duration)
+            .unwrap_or(chapter.start)
+            .max(chapter.start);
+        let element_id = format!("chp{index}");
+        tag.add_frame(id3::frame::Chapter {
+            element_id: element_id.clone(),
+            start_time: milliseconds(chapter.start),
+            end_time: milliseconds(end),
+            // Byte offsets aren't given, so players use the times.
+            start_offset: u32::MAX,
+            end_offset: u32::MAX,
+            frames: vec![Frame::text("TIT2", &chapter.title)],
+        });
+        elements.push(element_id);
+    }
+    tag.add_frame(TableOfContents {
+        element_id: String::from("toc"),
+        top_level: true,
+        ordered: true,
+        elements,
+        frames: vec![],
+    });
+    save_id3(path, &tag, readable)
+}
+
+/// Read a file's ID3 tag, or start a new one if it has none.
+///
+/// Also returns whether an existing tag could be read. If not, a warning is logged
+/// and the new tag must be put in front of the file by `save_id3`.
+fn read_id3(path: &Path) -> (Tag, bool) {
+    match Tag::read_from_path(path) {
+        Ok(tag) => (tag, true),
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => (Tag::new(), true),
+        Err(e) => {
+            log::warn!(
+                "Ignoring unreadable ID3 tag in {:?}: {e}",
+                path.to_string_lossy()
+            );
+            (Tag::new(), false)
+        }
+    }
+}
+
+/// Write an ID3v2.4 tag read by `read_id3` back to the file.
+///
+/// The tag is written to a copy of the file, which then replaces it, so the file
+/// is never left half-written if poddl is interrupted.
+fn save_id3(path: &Path, tag: &Tag, readable: bool) -> anyhow::Result<()> {
+    if !readable {
+        return prepend_id3(path, tag);
+    }
+    let temporary = temporary_path(path);
+    std::fs::copy(path, &temporary)?;
+    tag.write_to_path(&temporary, Version::Id3v24)?;
+    File::options().write(true).open(&temporary)?.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Write iTunes-style metadata items from the feed into an MP4 file.
+///
+/// As with ID3 tags, the items replace any the file already has, and the file's
+/// other items are kept.
+fn write_mp4(
+    path: &Path,
+    episode: &Episode,
+    podcast: &str,
+    cover: Option<&Artwork>,
+) -> anyhow::Result<()> {
+    let date = episode.date.timestamp().strftime("%Y-%m-%dT%H:%M:%SZ");
+    let mut items = vec![
+        MetadataItem::text(b"\xA9nam", &episode.title),
+        MetadataItem::text(b"\xA9alb", podcast),
+        MetadataItem::text(b"\xA9ART", podcast),
+        MetadataItem::text(b"\xA9day", &date.to_string()),
+    ];
+    if let Some(description) = &episode.description {
+        let short: String = description.chars().take(MP4_SHORT_DESCRIPTION).collect();
+        items.push(MetadataItem::text(b"desc", &short));
+        items.push(MetadataItem::text(b"ldes", description));
+    }
+    if let Some(number) = episode.number {
+        items.push(MetadataItem::track(number));
+    }
+    if let Some(cover) = cover {
+        items.push(MetadataItem::image(cover));
+    }
+    mp4::write_metadata(path, &items, &[*b"desc", *b"ldes", *b"trkn"])
+}
+
+/// Path of the temporary file a tagged copy of `path` is written to, before it
+/// replaces the original.
+pub(crate) fn temporary_path(path: &Path) -> std::path::PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tagging");
+    path.with_file_name(name)
+}
+
+/// Write a new tag in front of a file's contents.
+///
+/// Used when the file starts with a broken tag, which the id3 crate won't replace.
+/// Its size can't be trusted, so rather than risk removing audio it is left behind
+/// the new tag, where players skip it.
+fn prepend_id3(path: &Path, tag: &Tag) -> anyhow::Result<()> {
+    let temporary = temporary_path(path);
+    let mut output = BufWriter::new(File::create(&temporary)?);
+    tag.write_to(&mut output, Version::Id3v24)?;
+    std::io::copy(&mut File::open(path)?, &mut output)?;
+    output.into_inner()?.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
@@ -0,0 +1,437 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+
+use crate::Episode;
+use crate::cli::EpisodeType;
+use crate::media::UNKNOWN_EXTENSION;
+
+/// 255 is the truncate limit for sanitize_filename, and a common filesystem limit
+/// on the length of a single path component.
+const COMPONENT_LIMIT: usize = 255;
+
+/// A template for the path of each episode's file within the output directory.
+///
+/// Templates contain literal text and variables in braces, such as
+/// `{podcast}/{date:%Y}/{season:02}x{episode:03} - {title}.{ext}`. A `/` in the
+/// template separates directories, while any `/` in a variable's value is removed.
+///
+/// The variables are:
+///
+/// - `{podcast}`: the podcast (channel) title
+/// - `{title}`: the episode title
+/// - `{guid}`: the item GUID, or the enclosure URL if it has none
+/// - `{season}`, `{episode}`: the season and episode numbers, empty if unknown;
+///   a width such as `{episode:03}` pads the number with zeros. Episodes of feeds
+///   without episode numbers are numbered in order of publication
+/// - `{type}`: the episode type, `full`, `trailer` or `bonus`
+/// - `{date}`: the publication date as `YYYY-MM-DD`, or formatted with a strftime
+///   format such as `{date:%Y-%m}`
+/// - `{remote}`: the filename in the RSS feed enclosure URL
+/// - `{ext}`: the file extension matching the enclosure mime type, detected during
+///   the download if the feed doesn't identify the file type
+///
+/// Use `{{` and `}}` for literal braces.
+#[derive(Debug, Clone)]
+pub(crate) struct FilenameTemplate {
+    /// Segments of each path component.
+    components: Vec<Vec<Segment>>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Variable(Variable),
+}
+
+#[derive(Debug, Clone)]
+enum Variable {
+    Podcast,
+    Title,
+    Guid,
+    Season { width: usize },
+    Episode { width: usize },
+    Type,
+    Date { format: String },
+    Remote,
+    Extension,
+}
+
+impl FilenameTemplate {
+    /// The episode's ISO date followed by its title, eg "2025-10-21 - Title.mp3".
+    pub(crate) const DATE_AND_TITLE: &str = "{date} - {title}.{ext}";
+    /// The filename that appears in the RSS feed enclosure.
+    pub(crate) const REMOTE: &str = "{remote}";
+
+    /// Produce the path for the episode, relative to the output directory.
+    ///
+    /// Each path component is limited in length by truncating the episode title.
+    pub(crate) fn render(&self, episode: &Episode, podcast: &str) -> PathBuf {
+        self.components
+            .iter()
+            .map(|segments| render_component(segments, episode, podcast))
+            .collect()
+    }
+}
+
+/// Render one path component, truncating any titles to fit the length limit.
+fn render_component(segments: &[Segment], episode: &Episode, podcast: &str) -> String {
+    let mut fixed_length = 0;
+    let mut title_count = 0;
+    let rendered: Vec<Option<String>> = segments
+        .iter()
+        .map(|segment| {
+            let value = match segment {
+                Segment::Variable(Variable::Title) => {
+                    title_count += 1;
+                    return None;
+                }
+                Segment::Literal(text) => text.clone(),
+                Segment::Variable(variable) => {
+                    sanitize_filename::sanitize(variable.value(episode, podcast))
+                }
+            };
+            fixed_length += value.len();
+            Some(value)
+        })
+        .collect();
+
+    // Limit the length of the filename part used by the title, sharing what is left
+    // after the other segments between each use of the title.
+    let title = match COMPONENT_LIMIT
+        .saturating_sub(fixed_length)
+        .checked_div(title_count)
+    {
+        Some(limit) => {
+            let title = sanitize_filename::sanitize(&episode.title);
+            let mut limit = limit.min(title.len());
+            while !title.is_char_boundary(limit) {
+                limit -= 1;
+            }
+            title[..limit].to_owned()
+        }
+        None => String::new(),
+    };
+
+    let component: String = rendered
+        .into_iter()
+        .map(|value| value.unwrap_or_else(|| title.clone()))
+        .collect();
+    let component = sanitize_filename::sanitize(component);
+    if component.is_empty() {
+        String::from("_")
+    } else {
+        component
+    }
+}
+
+impl Variable {
+    fn value(&self, episode: &Episode, podcast: &str) -> String {
+        match self {
+            Variable::Podcast => podcast.to_owned(),
+            Variable::Title => episode.title.clone(),
+            Variable::Guid => episode.archive_id().to_owned(),
+            Variable::Season { width } => pad_number(episode.season, *width),
+            Variable::Episode { width } => pad_number(episode.number, *width),
+            Variable::Type => match episode.episode_type {
+                EpisodeType::Full => "full",
+                EpisodeType::Trailer => "trailer",
+                EpisodeType::Bonus => "bonus",
+            }
+            .to_owned(),
+            Variable::Date { format } => {
+                jiff::fmt::strtime::format(format, &episode.date).unwrap_or_default()
+            }
+            Variable::Remote => episode.existing_filename(),
+            Variable::Extension => episode.extension().unwrap_or(UNKNOWN_EXTENSION).to_owned(),
+        }
+    }
+
+    fn parse(name: &str, spec: Option<&str>) -> anyhow::Result<Self> {
+        let width = || -> anyhow::Result<usize> {
+            spec.map_or(Ok(0), |spec| {
+                spec.parse()
+                    .map_err(|_| anyhow!("Invalid width {spec:?} for {{{name}}}"))
+            })
+        };
+        let variable = match name {
+            "podcast" => Variable::Podcast,
+            "title" => Variable::Title,
+            "guid" => Variable::Guid,
+            "type" => Variable::Type,
+            "season" => return Ok(Variable::Season { width: width()? }),
+            "episode" => return Ok(Variable::Episode { width: width()? }),
+            "date" => {
+                let format = spec.unwrap_or("%F").to_owned();
+                // Check the format now rather than for every episode.
+                jiff::fmt::strtime::format(&format, &jiff::Zoned::now())
+                    .map_err(|e| anyhow!("Invalid date format {format:?}: {e}"))?;
+                return Ok(Variable::Date { format });
+            }
+            "remote" => Variable::Remote,
+            "ext" => Variable::Extension,
+            _ => bail!("Unknown template variable {{{name}}}"),
+        };
+        if spec.is_some() {
+            bail!("Template variable {{{name}}} does not take a format");
+        }
+        Ok(variable)
+    }
+}
+
+/// Format a number padded with zeros to at least `width` digits.
+fn pad_number(number: Option<u32>, width: usize) -> String {
+    number.map_or_else(String::new, |n| format!("{n:0width$}"))
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut components = vec![];
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inner.push(c),
+                            None => bail!("Unclosed {{ in filename template"),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    let (name, spec) = match inner.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec)),
+                        None => (inner.as_str(), None),
+                    };
+                    segments.push(Segment::Variable(Variable::parse(name.trim(), spec)?));
+                }
+                '}' => bail!("Unmatched }} in filename template"),
+                '/' => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    components.push(std::mem::take(&mut segments));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        components.push(segments);
+
+        for component in &components {
+            match component.as_slice() {
+                [] => bail!("Filename template has an empty path component"),
+                [Segment::Literal(text)] if text == "." || text == ".." => {
+                    bail!("Filename template may not contain {text:?} path components")
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { components })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An episode with the given title, published on 5 March 2024.
+    fn episode(title: &str) -> Episode {
+        Episode {
+            title: title.to_owned(),
+            guid: Some(String::from("guid-1")),
+            audio_url: "https://example.com/audio/episode-1.mp3".parse().unwrap(),
+            size: None,
+            date: "2024-03-05T10:00:00+00:00[UTC]".parse().unwrap(),
+            mime_type: String::from("audio/mpeg"),
+            season: Some(2),
+            number: Some(7),
+            episode_type: EpisodeType::Full,
+            description: None,
+            image: None,
+            transcripts: vec![],
+            chapters: None,
+            duration: None,
+        }
+    }
+
+    /// Render the template for the episode, as a `/`-separated path.
+    fn render(template: &str, episode: &Episode) -> String {
+        let template: FilenameTemplate = template.parse().unwrap();
+        let path = template.render(episode, "The Podcast");
+        let components: Vec<_> = path.iter().map(|c| c.to_str().unwrap()).collect();
+        components.join("/")
+    }
+
+    #[test]
+    fn renders_variables() {
+        let episode = episode("Title");
+        assert_eq!(
+            render(FilenameTemplate::DATE_AND_TITLE, &episode),
+            "2024-03-05 - Title.mp3"
+        );
+        assert_eq!(
+            render("{podcast}/{date:%Y}/{type} {guid}.{ext}", &episode),
+            "The Podcast/2024/full guid-1.mp3"
+        );
+        assert_eq!(render(FilenameTemplate::REMOTE, &episode), "episode-1.mp3");
+        assert_eq!(render("{{{title}}}.{ext}", &episode), "{Title}.mp3");
+    }
+
+    #[test]
+    fn pads_numbers() {
+        let mut episode = episode("Title");
+        assert_eq!(
+            render("{season:02}x{episode:03}.{ext}", &episode),
+            "02x007.mp3"
+        );
+        assert_eq!(render("{season}x{episode}.{ext}", &episode), "2x7.mp3");
+        episode.number = Some(1234);
+        assert_eq!(render("{episode:03}.{ext}", &episode), "1234.mp3");
+        episode.season = None;
+        assert_eq!(render("{season:02}-{episode}.{ext}", &episode), "-1234.mp3");
+    }
+
+    #[test]
+    fn renders_unknown_extension() {
+        let mut episode = episode("Title");
+        episode.mime_type = String::new();
+        episode.audio_url = "https://example.com/stream".parse().unwrap();
+        assert_eq!(render("{title}.{ext}", &episode), "Title.unknown");
+    }
+
+    #[test]
+    fn removes_slashes_from_values() {
+        let episode = episode("AC/DC: Live");
+        assert_eq!(render("{title}.{ext}", &episode), "ACDC Live.mp3");
+        let template: FilenameTemplate = "{podcast}/{title}.{ext}".parse().unwrap();
+        let path = template.render(&episode, "Rock/Pop");
+        assert_eq!(path, PathBuf::from("RockPop").join("ACDC Live.mp3"));
+    }
+
+    #[test]
+    fn replaces_empty_components() {
+        let mut episode = episode("Title");
+        episode.season = None;
+        assert_eq!(render("{season}/{title}.{ext}", &episode), "_/Title.mp3");
+        assert_eq!(render("{season}", &episode), "_");
+    }
+
+    #[test]
+    fn truncates_long_titles() {
+        // The cut point falls in the middle of the two-byte "é", which is dropped.
+        let title = format!("{}é{}", "a".repeat(250), "b".repeat(100));
+        let rendered = render("{title}.{ext}", &episode(&title));
+        assert_eq!(rendered, format!("{}.mp3", "a".repeat(250)));
+
+        let rendered = render("{date} - {title}.{ext}", &episode(&"x".repeat(300)));
+        assert_eq!(rendered.len(), COMPONENT_LIMIT);
+        assert!(rendered.starts_with("2024-03-05 - xxx") && rendered.ends_with("x.mp3"));
+    }
+
+    #[test]
+    fn shares_length_limit_between_titles() {
+        let rendered = render("{title} {title}", &episode(&"x".repeat(300)));
+        assert_eq!(rendered, format!("{0} {0}", "x".repeat(127)));
+        // Titles are only truncated within their own component.
+        let rendered = render("{title}/{title}.{ext}", &episode(&"x".repeat(300)));
+        assert_eq!(
+            rendered,
+            format!("{}/{}.mp3", "x".repeat(255), "x".repeat(251))
+        );
+    }
+
+    /// The error message for an invalid template.
+    fn error(template: &str) -> String {
+        match template.parse::<FilenameTemplate>() {
+            Ok(parsed) => panic!("{template:?} parsed as {parsed:?}"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_valid_template() {
+        let template = "{podcast}/{date:%Y}/{season:02}x{episode:03} - {{{title}}}.{ext}";
+        assert!(template.parse::<FilenameTemplate>().is_ok());
+    }
+
+    #[test]
+    fn rejects_unclosed_brace() {
+        assert_eq!(error("{title"), "Unclosed { in filename template");
+    }
+
+    #[test]
+    fn rejects_unmatched_brace() {
+        assert_eq!(error("title}.{ext}"), "Unmatched } in filename template");
+    }
+
+    #[test]
+    fn rejects_unknown_variable() {
+        assert_eq!(
+            error("{author}.{ext}"),
+            "Unknown template variable {author}"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_width() {
+        assert_eq!(
+            error("{episode:x}.{ext}"),
+            "Invalid width \"x\" for {episode}"
+        );
+        assert_eq!(
+            error("{season:-2}.{ext}"),
+            "Invalid width \"-2\" for {season}"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_date_format() {
+        assert!(error("{date:%J}.{ext}").starts_with("Invalid date format \"%J\""));
+        assert!(error("{date:%Y-%}.{ext}").starts_with("Invalid date format \"%Y-%\""));
+    }
+
+    #[test]
+    fn rejects_format_for_variable_without_one() {
+        assert_eq!(
+            error("{title:03}.{ext}"),
+            "Template variable {title} does not take a format"
+        );
+    }
+
+    #[test]
+    fn rejects_empty_and_relative_components() {
+        assert_eq!(error(""), "Filename template has an empty path component");
+        assert_eq!(
+            error("{podcast}//{title}.{ext}"),
+            "Filename template has an empty path component"
+        );
+        assert_eq!(
+            error("../{title}.{ext}"),
+            "Filename template may not contain \"..\" path components"
+        );
+        assert_eq!(
+            error("./{title}.{ext}"),
+            "Filename template may not contain \".\" path components"
+        );
+    }
+}
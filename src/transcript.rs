@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use rss::extension::Extension;
+use url::Url;
+
+use crate::cli::TranscriptFormat;
+use crate::{Episode, http};
+
+/// Formats poddl saves, in the order they are chosen when the preferred format
+/// isn't available.
+const FORMATS: &[TranscriptFormat] = &[
+    TranscriptFormat::Vtt,
+    TranscriptFormat::Srt,
+    TranscriptFormat::Json,
+    TranscriptFormat::Html,
+];
+
+/// A transcript of an episode, from a `<podcast:transcript>` element.
+#[derive(Debug, Clone)]
+pub(crate) struct Transcript {
+    pub url: Url,
+    /// Mime type of the transcript, from the element's `type` attribute.
+    pub mime_type: String,
+}
+
+impl Transcript {
+    /// Read a `<podcast:transcript>` element, which must have a valid URL.
+    pub(crate) fn from_extension(extension: &Extension) -> Option<Self> {
+        let url = extension.attrs().get("url")?.trim().parse().ok()?;
+        let mime_type = extension.attrs().get("type").cloned().unwrap_or_default();
+        Some(Self { url, mime_type })
+    }
+
+    /// Format of the transcript, if it is one poddl saves.
+    fn format(&self) -> Option<TranscriptFormat> {
+        let essence = self
+            .mime_type
+            .split(';')
+            .next()?
+            .trim()
+            .to_ascii_lowercase();
+        match essence.as_str() {
+            "text/vtt" => Some(TranscriptFormat::Vtt),
+            "application/x-subrip" | "application/srt" | "text/srt" => Some(TranscriptFormat::Srt),
+            "application/json" => Some(TranscriptFormat::Json),
+            "text/html" => Some(TranscriptFormat::Html),
+            _ => None,
+        }
+    }
+}
+
+impl TranscriptFormat {
+    /// Extension of transcript files of this format.
+    fn extension(self) -> &'static str {
+        match self {
+            TranscriptFormat::Vtt => "vtt",
+            TranscriptFormat::Srt => "srt",
+            TranscriptFormat::Json => "json",
+            TranscriptFormat::Html => "html",
+        }
+    }
+}
+
+/// Choose the episode's transcript in the preferred format, or else the first
+/// available of the other formats.
+fn choose(
+    transcripts: &[Transcript],
+    preferred: TranscriptFormat,
+) -> Option<(&Transcript, TranscriptFormat)> {
+    std::iter::once(preferred)
+        .chain(FORMATS.iter().copied())
+        .find_map(|format| {
+            transcripts
+                .iter()
+                .find(|t| t.format() == Some(format))
+                .map(|t| (t, format))
+        })
+}
+
+/// Save the episode's transcript next to its audio file, with the same name and the
+/// transcript format's extension.
+///
+/// Episodes without a transcript in a format poddl saves are skipped. An existing
+/// transcript is kept unless `replace` is true, for when the audio has been
+/// downloaded again.
+pub(crate) fn save_transcript(
+    episode: &Episode,
+    audio_file: &Path,
+    preferred: TranscriptFormat,
+    replace: bool,
+) -> anyhow::Result<()> {
+    let Some((transcript, format)) = choose(&episode.transcripts, preferred) else {
+        return Ok(());
+    };
+    let path = audio_file.with_extension(format.extension());
+    if !replace && path.exists() {
+        return Ok(());
+    }
+    let body = http::agent()
+        .get(transcript.url.as_str())
+        .call()?
+        .into_body();
+    let bytes = http::read_body(body, http::MAX_BODY_SIZE)?;
+    std::fs::write(&path, bytes)?;
+    log::info!("Saved transcript to {:?}", path.to_string_lossy());
+    Ok(())
+}
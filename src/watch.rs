@@ -0,0 +1,70 @@
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::cli::{DownloadArgs, WatchOptions};
+
+/// Set when SIGINT or SIGTERM asks poddl to stop watching.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Proportion of the interval by which each wait is randomly lengthened or
+/// shortened, so many feeds or many poddl processes don't poll in lockstep.
+const JITTER: f64 = 0.1;
+
+/// Whether poddl has been asked to shut down.
+///
+/// Download threads check this before starting each episode, so downloads already
+/// in progress are finished.
+pub(crate) fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::Relaxed)
+}
+
+/// Download the feeds' episodes, then keep checking the feeds at an interval and
+/// download new episodes as they appear.
+///
+/// Runs until SIGINT or SIGTERM is received. The first signal lets any downloads in
+/// progress finish, leaving other episodes for the next run, while a second exits
+/// immediately, leaving partial files to be resumed. Errors loading a feed are
+/// logged and the feed is checked again after the next interval.
+pub(crate) fn watch(args: &DownloadArgs, options: &WatchOptions) -> anyhow::Result<ExitCode> {
+    let (stop, stopped) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        if SHUTDOWN.swap(true, Ordering::Relaxed) {
+            log::warn!("Exiting without waiting for downloads to finish");
+            std::process::exit(130);
+        }
+        log::info!("Shutting down after downloads in progress have finished");
+        let _ = stop.send(());
+    })
+    .context("Failed to set signal handler")?;
+
+    loop {
+        if let Err(e) = crate::run(args, None) {
+            log::error!("{e:#}");
+        }
+        if shutdown_requested() {
+            break;
+        }
+        let delay = jittered(options.interval);
+        log::info!("Checking again in {}", format_duration(delay));
+        // Either a signal arrives or the wait times out.
+        if stopped.recv_timeout(delay).is_ok() {
+            break;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Lengthen or shorten the interval by a random amount of up to JITTER.
+fn jittered(interval: Duration) -> Duration {
+    interval.mul_f64(1.0 + JITTER * (2.0 * fastrand::f64() - 1.0))
+}
+
+/// Format a duration to the nearest second, eg "29m 42s".
+fn format_duration(duration: Duration) -> String {
+    let seconds = jiff::SignedDuration::from_secs(duration.as_secs() as i64);
+    format!("{seconds:#}")
+}